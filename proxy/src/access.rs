@@ -0,0 +1,135 @@
+use actix_web::{
+    dev::Payload,
+    error,
+    web,
+    Error,
+    FromRequest,
+    HttpRequest,
+};
+use futures::future::{
+    err,
+    ok,
+    LocalBoxFuture,
+    Ready,
+};
+use super::cors::check_key_origin;
+use super::credentials::{
+    api_key,
+    parse_key,
+    unauthorized,
+};
+use super::error::JsonError;
+use super::trace::RequestContext;
+use super::middlewares::KeyValidator;
+
+/// Token that grants access to the proxy's operational endpoints, disabled when unset
+pub struct AdminToken(pub Option<String>);
+
+impl AdminToken {
+    fn matches(&self, candidate: &str) -> bool {
+        match &self.0 {
+            Some(token) => constant_time_eq(token.as_bytes(), candidate.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn forbidden(msg: &str) -> JsonError {
+    JsonError {
+        msg: msg.to_string(),
+        status: 403,
+        success: false,
+        retry_after: None,
+    }
+}
+
+fn bad_request(msg: &str) -> JsonError {
+    JsonError {
+        msg: msg.to_string(),
+        status: 400,
+        success: false,
+        retry_after: None,
+    }
+}
+
+/// The admin token is only taken from headers, a query string ends up in too many logs
+fn is_admin(req: &HttpRequest) -> bool {
+    let admin_token = req.app_data::<web::Data<AdminToken>>();
+    match (admin_token, api_key(req.headers(), "", None)) {
+        (Some(admin_token), Ok(Some(candidate))) => admin_token.matches(&candidate),
+        _ => false,
+    }
+}
+
+/// Extractor that only succeeds for requests carrying the admin token
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if is_admin(req) {
+            ok(Admin)
+        } else {
+            err(forbidden("Admin credentials are required").into())
+        }
+    }
+}
+
+/// Who is calling an endpoint that is open to both admins and key holders
+pub enum Caller {
+    Admin,
+    /// A validated API key, which may only see its own data
+    Key(String),
+}
+
+impl Caller {
+    /// The key this caller's results must be limited to, `None` for admins
+    pub fn scope(&self) -> Option<&str> {
+        match self {
+            Caller::Admin => None,
+            Caller::Key(key) => Some(key),
+        }
+    }
+
+    /// The canonical form of `key`, failing unless the caller may see data belonging to it
+    pub fn check_key(&self, key: &str) -> Result<String, JsonError> {
+        let key = parse_key(key).map_err(|_| bad_request("APIKey is malformed"))?;
+        match self.scope() {
+            Some(own) if own != key => Err(forbidden("APIKey may only access its own requests")),
+            _ => Ok(key),
+        }
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if is_admin(req) {
+            return Box::pin(ok(Caller::Admin));
+        }
+        let headers = req.headers().clone();
+        let apikey = api_key(&headers, req.query_string(), req.app_data());
+        let validator = req.app_data::<web::Data<KeyValidator>>().cloned();
+        let context = req.extensions().get::<RequestContext>().cloned();
+        Box::pin(async move {
+            let apikey = parse_key(&apikey?.ok_or_else(|| unauthorized("APIKey is required"))?)?;
+            let validator = validator.ok_or_else(|| error::ErrorInternalServerError("APIKey validation is not configured"))?;
+            let status = validator.check(&apikey, context.as_ref()).await?;
+            check_key_origin(&status, &headers)?;
+            Ok(Caller::Key(apikey))
+        })
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+struct Inner {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+/// Consecutive-failure circuit breaker. Once open, calls are refused until
+/// `open_duration` has passed, then a single probe decides whether it closes again
+pub struct CircuitBreaker {
+    inner: Mutex<Inner>,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        CircuitBreaker {
+            inner: Mutex::new(Inner {
+                consecutive_failures: 0,
+                opened_at: None,
+                probing: false,
+            }),
+            failure_threshold,
+            open_duration,
+        }
+    }
+
+    /// Whether a call may be attempted right now
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => true,
+            Some(opened_at) if opened_at.elapsed() >= self.open_duration && !inner.probing => {
+                inner.probing = true;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.probing = false;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.probing = false;
+        if inner.consecutive_failures >= self.failure_threshold {
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    /// Like `allow`, the call's outcome is recorded through the returned `Attempt`
+    pub fn attempt(&self) -> Option<Attempt<'_>> {
+        if self.allow() {
+            Some(Attempt {
+                breaker: self,
+                settled: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Let another probe through when one ended without a verdict, e.g. the client went away
+    pub fn release_probe(&self) {
+        self.inner.lock().unwrap().probing = false;
+    }
+
+    pub fn state(&self) -> BreakerState {
+        let inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if opened_at.elapsed() >= self.open_duration => BreakerState::HalfOpen,
+            Some(_) => BreakerState::Open,
+        }
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.inner.lock().unwrap().consecutive_failures
+    }
+
+    /// Time until the next probe is allowed, zero when the breaker is closed
+    pub fn retry_after(&self) -> Duration {
+        let inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            Some(opened_at) => self.open_duration
+                .checked_sub(opened_at.elapsed())
+                .unwrap_or_default(),
+            None => Duration::from_secs(0),
+        }
+    }
+}
+
+/// A call the breaker let through. Dropping it without an outcome, e.g. because the request
+/// future was cancelled, frees the probe slot so the breaker doesn't stay half-open forever
+pub struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    settled: bool,
+}
+
+impl Attempt<'_> {
+    pub fn record_success(mut self) {
+        self.settled = true;
+        self.breaker.record_success();
+    }
+
+    pub fn record_failure(mut self) {
+        self.settled = true;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.breaker.release_probe();
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use lru::LruCache;
+
+/// Outcome of validating an API key against the authentication service
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Validation {
+    Valid,
+    Disabled,
+    Invalid,
+}
+
+/// What the authentication service said about a key
+#[derive(Clone, Debug)]
+pub struct KeyStatus {
+    pub validation: Validation,
+    /// Browser origins the key may be used from, any origin when `None`
+    pub allowed_origins: Option<Arc<Vec<String>>>,
+    /// Name of the endpoint policy scope the key belongs to
+    pub scope: Option<Arc<str>>,
+    /// Largest request body the key may send, the policy's limit for its scope when `None`
+    pub max_upload_bytes: Option<u64>,
+}
+
+impl KeyStatus {
+    pub fn rejected(validation: Validation) -> Self {
+        KeyStatus {
+            validation,
+            allowed_origins: None,
+            scope: None,
+            max_upload_bytes: None,
+        }
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        match &self.allowed_origins {
+            Some(origins) => origins.iter().any(|allowed| allowed == "*" || allowed == origin),
+            None => true,
+        }
+    }
+}
+
+struct Entry {
+    status: KeyStatus,
+    validated_at: Instant,
+}
+
+/// In-process LRU cache of API key validation results, shared by all workers
+pub struct AuthCache {
+    entries: Mutex<LruCache<String, Entry>>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+}
+
+impl AuthCache {
+    pub fn new(capacity: usize, positive_ttl: Duration, negative_ttl: Duration) -> Self {
+        AuthCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            positive_ttl,
+            negative_ttl,
+        }
+    }
+
+    /// Get the cached validation for a key, if present and not expired.
+    /// Expired entries stay in the cache until evicted, see `valid_within`
+    pub fn get(&self, key: &str) -> Option<KeyStatus> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key.to_string())?;
+        let ttl = match entry.status.validation {
+            Validation::Valid => self.positive_ttl,
+            Validation::Disabled | Validation::Invalid => self.negative_ttl,
+        };
+        if entry.validated_at.elapsed() < ttl {
+            Some(entry.status.clone())
+        } else {
+            None
+        }
+    }
+
+    /// The key's status if it was last seen valid less than `max_age` ago, even if the entry expired
+    pub fn valid_within(&self, key: &str, max_age: Duration) -> Option<KeyStatus> {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .get(&key.to_string())
+            .filter(|entry| entry.status.validation == Validation::Valid && entry.validated_at.elapsed() < max_age)
+            .map(|entry| entry.status.clone())
+    }
+
+    /// Store a validation result, valid keys and rejected keys expire separately
+    pub fn insert(&self, key: &str, status: KeyStatus) {
+        let entry = Entry {
+            status,
+            validated_at: Instant::now(),
+        };
+        self.entries.lock().unwrap().put(key.to_string(), entry);
+    }
+
+    /// Drop a key so the next request is validated against the authentication service
+    pub fn invalidate(&self, key: &str) -> bool {
+        self.entries.lock().unwrap().pop(&key.to_string()).is_some()
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    net::IpAddr,
+    time::Duration,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use actix_web::http::Method;
+use url::Url;
+pub use service_common::config::{
+    exit_with_error,
+    optional,
+    Args,
+};
+use service_common::config::{
+    is_address,
+    redact_userinfo,
+    REDACTED,
+};
+use super::cors::CorsPolicy;
+use super::forwarding::{
+    RetryPolicy,
+    RouteTimeouts,
+};
+use super::headers::TrustedProxies;
+use super::outage::OutagePolicy;
+use super::policy::EndpointPolicy;
+use super::streams::{
+    StreamPolicy,
+    LONG_LIVED_PATHS,
+    POLLING_PATHS,
+};
+use super::trace::TraceExport;
+use super::upstream::Balancing;
+use super::writer::OverflowPolicy;
+
+/// Environment variables are named `PROXY_<SETTING>`, e.g. `PROXY_LISTEN_ADDRESS`
+pub const ENV_PREFIX: &str = "PROXY";
+const REQUEST_LOG_SINKS: &[&str] = &["mongodb", "file", "stdout", "memory"];
+/// Longest a validated key may be cached when the key service can't push invalidations
+const MAX_UNINVALIDATED_TTL_SECS: u64 = 5;
+/// A gateway read checked against the endpoint policy when the content cache is on
+const CONTENT_PROBE_PATH: &str = "/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+/// Proxy settings, layered from defaults, a TOML file, `PROXY_*` environment variables and
+/// `--setting-name value` flags, each overriding the one before
+///
+/// Empty strings leave optional settings such as `tls_cert` unset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen_address: String,
+    pub tls_cert: String,
+    pub tls_key: String,
+    pub tls_reload_interval_secs: u64,
+    pub mongodb_uri: String,
+    pub mongodb_database: String,
+    pub admin_token: String,
+    pub key_hash_secret: String,
+    /// Accept API keys in the `api_key` query parameter as well as in headers
+    pub api_key_query_param: bool,
+    /// `http(s)://host:port`, or `host:port` for https when `upstream_ca_bundle` is set and http otherwise
+    pub upstreams: Vec<String>,
+    pub upstream_ca_bundle: String,
+    pub upstream_balancing: String,
+    pub upstream_breaker_threshold: u32,
+    pub upstream_breaker_open_secs: u64,
+    pub upstream_retry_attempts: u32,
+    pub upstream_retry_base_backoff_ms: u64,
+    pub upstream_retry_max_backoff_ms: u64,
+    pub upstream_probe_interval_secs: u64,
+    pub upstream_probe_timeout_secs: u64,
+    /// `PATH:CONNECT_SECS:READ_SECS` for forwarded calls, the first route whose path matches
+    /// applies and `*` matches any run of characters
+    pub upstream_route_timeouts: Vec<String>,
+    pub trusted_proxies: Vec<String>,
+    pub content_cache_dir: String,
+    pub content_cache_memory_bytes: u64,
+    pub content_cache_disk_bytes: u64,
+    pub content_cache_max_entry_bytes: u64,
+    /// `http(s)://host:port`, or `host:port` for https when `auth_ca_bundle` is set and http otherwise
+    pub auth_address: String,
+    pub auth_ca_bundle: String,
+    pub auth_cache_capacity: usize,
+    /// Without `admin_token` the key service can't invalidate cached keys, so this is capped
+    /// at a few seconds to keep revocation prompt
+    pub auth_cache_positive_ttl_secs: u64,
+    pub auth_cache_negative_ttl_secs: u64,
+    pub auth_outage_policy: String,
+    pub auth_outage_grace_period_secs: u64,
+    pub auth_breaker_threshold: u32,
+    pub auth_breaker_open_secs: u64,
+    pub request_log_capacity: usize,
+    pub request_log_batch_size: usize,
+    pub request_log_flush_interval_ms: u64,
+    pub request_log_overflow: String,
+    pub request_log_spill_path: String,
+    pub request_log_sinks: Vec<String>,
+    pub request_log_file_path: String,
+    pub request_log_file_max_bytes: u64,
+    pub request_log_file_max_files: usize,
+    pub request_log_memory_capacity: usize,
+    pub trace_exporter: String,
+    pub trace_otlp_endpoint: String,
+    pub trace_batch_size: usize,
+    pub trace_flush_interval_secs: u64,
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_max_age_secs: u64,
+    /// JSON policy file, whose top-level `max_upload_bytes` is the org default body limit for
+    /// keys without a limit of their own or in their scope; uploads are unlimited without one
+    pub endpoint_policy: String,
+    pub stream_idle_timeout_secs: u64,
+    pub stream_max_per_key: usize,
+    /// Serve streaming endpoints as Server-Sent Events to `Accept: text/event-stream` GETs, which
+    /// the default endpoint policy allows and a policy file must allow too
+    pub stream_event_source: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_address: "127.0.0.1:5003".to_string(),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            tls_reload_interval_secs: 30,
+            mongodb_uri: "mongodb://localhost:27017".to_string(),
+            mongodb_database: "secure".to_string(),
+            admin_token: String::new(),
+            key_hash_secret: String::new(),
+            api_key_query_param: false,
+            upstreams: vec!["127.0.0.1:5001".to_string()],
+            upstream_ca_bundle: String::new(),
+            upstream_balancing: "round_robin".to_string(),
+            upstream_breaker_threshold: 3,
+            upstream_breaker_open_secs: 30,
+            upstream_retry_attempts: 3,
+            upstream_retry_base_backoff_ms: 100,
+            upstream_retry_max_backoff_ms: 2000,
+            upstream_probe_interval_secs: 10,
+            upstream_probe_timeout_secs: 2,
+            upstream_route_timeouts: vec![
+                "/api/v0/add:5:600".to_string(),
+                "/api/v0/block/put:5:600".to_string(),
+                "/api/v0/dag/put:5:600".to_string(),
+                "/api/v0/pin/add:5:300".to_string(),
+                "*:5:60".to_string(),
+            ],
+            trusted_proxies: Vec::new(),
+            content_cache_dir: "content-cache".to_string(),
+            content_cache_memory_bytes: 64 * 1024 * 1024,
+            content_cache_disk_bytes: 1024 * 1024 * 1024,
+            content_cache_max_entry_bytes: 8 * 1024 * 1024,
+            auth_address: "127.0.0.1:5002".to_string(),
+            auth_ca_bundle: String::new(),
+            auth_cache_capacity: 10_000,
+            auth_cache_positive_ttl_secs: MAX_UNINVALIDATED_TTL_SECS,
+            auth_cache_negative_ttl_secs: 5,
+            auth_outage_policy: "fail_closed".to_string(),
+            auth_outage_grace_period_secs: 900,
+            auth_breaker_threshold: 5,
+            auth_breaker_open_secs: 30,
+            request_log_capacity: 10_000,
+            request_log_batch_size: 100,
+            request_log_flush_interval_ms: 1000,
+            request_log_overflow: "drop".to_string(),
+            request_log_spill_path: "requests-spill.jsonl".to_string(),
+            request_log_sinks: vec!["mongodb".to_string()],
+            request_log_file_path: "requests.jsonl".to_string(),
+            request_log_file_max_bytes: 100 * 1024 * 1024,
+            request_log_file_max_files: 5,
+            request_log_memory_capacity: 1000,
+            trace_exporter: "none".to_string(),
+            trace_otlp_endpoint: "http://127.0.0.1:4318/v1/traces".to_string(),
+            trace_batch_size: 100,
+            trace_flush_interval_secs: 5,
+            cors_allowed_origins: Vec::new(),
+            cors_max_age_secs: 600,
+            endpoint_policy: String::new(),
+            stream_idle_timeout_secs: 120,
+            stream_max_per_key: 4,
+            stream_event_source: false,
+        }
+    }
+}
+
+/// `host:port` or `http(s)://host:port`
+fn is_address_or_url(address: &str) -> bool {
+    match address.split_once("://") {
+        Some((scheme, rest)) => (scheme == "http" || scheme == "https") && is_address(rest),
+        None => is_address(address),
+    }
+}
+
+/// Whether `address` is reached over TLS by IP, which rustls can't verify a certificate for
+fn is_tls_by_ip(address: &str, ca_bundle: &str) -> bool {
+    let host_port = match address.split_once("://") {
+        Some(("https", rest)) => rest,
+        Some(_) => return false,
+        None if !ca_bundle.is_empty() => address,
+        None => return false,
+    };
+    host_port
+        .rsplit_once(':')
+        .is_some_and(|(host, _)| host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok())
+}
+
+/// `address` as a URL, a bare `host:port` gets `default_scheme`
+fn address_url(address: &str, default_scheme: &str) -> Result<Url, url::ParseError> {
+    if address.contains("://") {
+        Url::parse(address)
+    } else {
+        Url::parse(&format!("{}://{}", default_scheme, address))
+    }
+}
+
+impl Config {
+    /// Layer the TOML file from `--config` or `PROXY_CONFIG`, `PROXY_*` environment variables
+    /// and command line overrides over the defaults
+    pub fn load(args: &Args) -> Result<Self, String> {
+        args.load()
+    }
+
+    /// Check every setting, reporting all problems at once
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        if !is_address(&self.listen_address) {
+            errors.push(format!("listen_address must be host:port, got {:?}", self.listen_address));
+        }
+        if self.tls_cert.is_empty() != self.tls_key.is_empty() {
+            errors.push("tls_cert and tls_key must be set together".to_string());
+        }
+        if !self.mongodb_uri.starts_with("mongodb://") && !self.mongodb_uri.starts_with("mongodb+srv://") {
+            errors.push(format!("mongodb_uri must be a mongodb:// or mongodb+srv:// URI, got {:?}", self.mongodb_uri));
+        }
+        if self.mongodb_database.is_empty() {
+            errors.push("mongodb_database must not be empty".to_string());
+        }
+        if self.upstreams.is_empty() {
+            errors.push("upstreams must list at least one IPFS node".to_string());
+        }
+        for upstream in self.upstreams.iter().filter(|upstream| !is_address_or_url(upstream)) {
+            errors.push(format!("upstreams must be host:port or http(s)://host:port, got {:?}", upstream));
+        }
+        if !is_address_or_url(&self.auth_address) {
+            errors.push(format!("auth_address must be host:port or http(s)://host:port, got {:?}", self.auth_address));
+        }
+        for upstream in self.upstreams.iter().filter(|upstream| is_tls_by_ip(upstream, &self.upstream_ca_bundle)) {
+            errors.push(format!("upstreams reached over TLS must name a host, not an IP address, got {:?}", upstream));
+        }
+        if is_tls_by_ip(&self.auth_address, &self.auth_ca_bundle) {
+            errors.push(format!("auth_address reached over TLS must name a host, not an IP address, got {:?}", self.auth_address));
+        }
+        if Balancing::parse(&self.upstream_balancing).is_none() {
+            errors.push("upstream_balancing must be round_robin, least_connections or consistent_hash".to_string());
+        }
+        for route in self.upstream_route_timeouts.iter().filter(|route| RouteTimeouts::parse(route).is_none()) {
+            errors.push(format!("upstream_route_timeouts must be PATH:CONNECT_SECS:READ_SECS, got {:?}", route));
+        }
+        if TrustedProxies::parse(&self.trusted_proxies.join(",")).is_none() {
+            errors.push("trusted_proxies must be addresses or CIDR blocks".to_string());
+        }
+        if OutagePolicy::parse(&self.auth_outage_policy, Default::default()).is_none() {
+            errors.push("auth_outage_policy must be fail_closed or fail_open".to_string());
+        }
+        if self.admin_token.is_empty() {
+            if self.auth_cache_positive_ttl_secs > MAX_UNINVALIDATED_TTL_SECS {
+                errors.push(format!(
+                    "auth_cache_positive_ttl_secs over {} needs admin_token, so the key service can invalidate revoked keys",
+                    MAX_UNINVALIDATED_TTL_SECS,
+                ));
+            }
+            if self.auth_outage_policy == "fail_open" {
+                errors.push("auth_outage_policy fail_open needs admin_token, so the key service can invalidate revoked keys".to_string());
+            }
+        }
+        if OverflowPolicy::parse(&self.request_log_overflow, Default::default()).is_none() {
+            errors.push("request_log_overflow must be drop or spill".to_string());
+        }
+        if self.request_log_sinks.is_empty() {
+            errors.push("request_log_sinks must list at least one sink".to_string());
+        }
+        for sink in self.request_log_sinks.iter().filter(|sink| !REQUEST_LOG_SINKS.contains(&sink.as_str())) {
+            errors.push(format!("request_log_sinks must be mongodb, file, stdout or memory, got {:?}", sink));
+        }
+        if TraceExport::parse(&self.trace_exporter, &self.trace_otlp_endpoint).is_none() {
+            errors.push("trace_exporter must be none, stdout or otlp".to_string());
+        }
+        if Url::parse(&self.trace_otlp_endpoint).is_err() {
+            errors.push(format!("trace_otlp_endpoint must be a URL, got {:?}", self.trace_otlp_endpoint));
+        }
+        let positive = [
+            ("upstream_breaker_threshold", self.upstream_breaker_threshold as u64),
+            ("upstream_retry_attempts", self.upstream_retry_attempts as u64),
+            ("auth_breaker_threshold", self.auth_breaker_threshold as u64),
+            ("auth_cache_capacity", self.auth_cache_capacity as u64),
+            ("request_log_capacity", self.request_log_capacity as u64),
+            ("request_log_batch_size", self.request_log_batch_size as u64),
+            ("request_log_file_max_files", self.request_log_file_max_files as u64),
+            ("trace_batch_size", self.trace_batch_size as u64),
+            ("stream_idle_timeout_secs", self.stream_idle_timeout_secs),
+            ("stream_max_per_key", self.stream_max_per_key as u64),
+        ];
+        for (name, _) in positive.iter().filter(|(_, value)| *value == 0) {
+            errors.push(format!("{} must be at least 1", name));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// Read the endpoint policy file, the built-in allowlist applies when none is set
+    pub fn load_endpoint_policy(&self) -> Result<EndpointPolicy, String> {
+        match optional(&self.endpoint_policy) {
+            Some(path) => EndpointPolicy::load(path.as_ref()).map_err(|e| format!("endpoint_policy {:?}: {}", path, e)),
+            None => Ok(EndpointPolicy::default()),
+        }
+    }
+
+    /// Requests that features turned on here depend on but `policy` denies keys without a scope
+    pub fn policy_warnings(&self, policy: &EndpointPolicy) -> Vec<String> {
+        let mut needed = Vec::new();
+        if self.content_cache_memory_bytes > 0 || self.content_cache_disk_bytes > 0 {
+            needed.push(("content cache", Method::GET, CONTENT_PROBE_PATH, ""));
+            needed.push(("content cache", Method::POST, "/api/v0/cat", ""));
+        }
+        if self.stream_event_source {
+            needed.extend(LONG_LIVED_PATHS.iter().map(|path| ("stream_event_source", Method::GET, *path, "")));
+            needed.extend(POLLING_PATHS.iter().map(|path| ("stream_event_source", Method::GET, *path, "poll=true")));
+        }
+        needed
+            .into_iter()
+            .filter(|(_, method, path, query)| !policy.allows(method, path, query))
+            .map(|(feature, method, path, _)| format!("{} is on but endpoint_policy denies {} {}", feature, method, path))
+            .collect()
+    }
+
+    /// Upstream URLs, upstreams given as `host:port` use `default_scheme`
+    pub fn upstream_urls(&self, default_scheme: &str) -> Result<Vec<Url>, String> {
+        self.upstreams
+            .iter()
+            .map(|address| address_url(address, default_scheme).map_err(|e| format!("upstreams {:?}: {}", address, e)))
+            .collect()
+    }
+
+    /// The authentication service's URL, `default_scheme` applies to a bare `host:port`
+    pub fn auth_url(&self, default_scheme: &str) -> Result<Url, String> {
+        address_url(&self.auth_address, default_scheme).map_err(|e| format!("auth_address {:?}: {}", self.auth_address, e))
+    }
+
+    pub fn balancing(&self) -> Balancing {
+        Balancing::parse(&self.upstream_balancing)
+            .expect("upstream_balancing must be round_robin, least_connections or consistent_hash")
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.upstream_retry_attempts,
+            base_backoff: Duration::from_millis(self.upstream_retry_base_backoff_ms),
+            max_backoff: Duration::from_millis(self.upstream_retry_max_backoff_ms),
+        }
+    }
+
+    pub fn route_timeouts(&self) -> Vec<RouteTimeouts> {
+        self.upstream_route_timeouts
+            .iter()
+            .map(|route| RouteTimeouts::parse(route).expect("upstream_route_timeouts must be PATH:CONNECT_SECS:READ_SECS"))
+            .collect()
+    }
+
+    pub fn cors_policy(&self) -> CorsPolicy {
+        CorsPolicy::parse(&self.cors_allowed_origins.join(","), Duration::from_secs(self.cors_max_age_secs))
+    }
+
+    pub fn stream_policy(&self) -> StreamPolicy {
+        StreamPolicy {
+            idle_timeout: Duration::from_secs(self.stream_idle_timeout_secs),
+            max_per_key: self.stream_max_per_key,
+            event_stream: self.stream_event_source,
+        }
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        OverflowPolicy::parse(&self.request_log_overflow, self.request_log_spill_path.clone().into())
+            .expect("request_log_overflow must be drop or spill")
+    }
+
+    /// The effective configuration as TOML, with secrets redacted
+    pub fn render(&self) -> String {
+        let mut shown = self.clone();
+        let redact = |secret: &mut String| {
+            if !secret.is_empty() {
+                *secret = REDACTED.to_string();
+            }
+        };
+        redact(&mut shown.admin_token);
+        redact(&mut shown.key_hash_secret);
+        shown.mongodb_uri = redact_userinfo(&shown.mongodb_uri);
+        shown.trace_otlp_endpoint = redact_userinfo(&shown.trace_otlp_endpoint);
+        shown.auth_address = redact_userinfo(&shown.auth_address);
+        shown.upstreams = shown.upstreams.iter().map(|upstream| redact_userinfo(upstream)).collect();
+        toml::to_string(&shown).expect("the configuration serializes to TOML")
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    pin::Pin,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    task::{
+        Context,
+        Poll,
+    },
+    time::SystemTime,
+};
+use actix_web::{
+    http::{
+        header::HeaderMap,
+        Method,
+    },
+    rt,
+    web::{
+        self,
+        Bytes,
+        BytesMut,
+    },
+};
+use futures::Stream;
+use lru::LruCache;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use super::upstream::cid_from_request;
+
+/// Response headers that describe the connection rather than the content
+const UNCACHED_HEADERS: &[&str] = &["connection", "keep-alive", "transfer-encoding", "content-length", "date", "trailer"];
+
+/// The cache key and CID for reads of immutable content, `GET /ipfs/<cid>/...` and
+/// `/api/v0/cat?arg=<cid>`, which the IPFS API takes as a POST
+pub fn cache_key(method: &Method, path: &str, query: Option<&str>) -> Option<(String, String)> {
+    let cacheable = match *method {
+        Method::GET => path.starts_with("/ipfs/") || path == "/api/v0/cat",
+        Method::POST => path == "/api/v0/cat",
+        _ => false,
+    };
+    if !cacheable {
+        return None;
+    }
+    let cid = cid_from_request(path, query)?;
+    let mut params: Vec<(String, String)> = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect();
+    params.sort();
+    let query = url::form_urlencoded::Serializer::new(String::new()).extend_pairs(params).finish();
+    Some((format!("{}?{}", path, query), cid))
+}
+
+/// The body length a response announced, Kubo sends `X-Content-Length` on streamed cat output
+pub fn announced_length(headers: &HeaderMap) -> Option<u64> {
+    ["content-length", "x-content-length"]
+        .iter()
+        .find_map(|name| headers.get(*name)?.to_str().ok()?.parse().ok())
+}
+
+/// Whether the upstream may report a failure after the body has started, in an `X-Stream-Error`
+/// trailer the proxy never sees
+pub fn may_fail_in_trailer(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("trailer")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("x-stream-error"))
+}
+
+/// Whether an upstream response header should be stored with the content
+pub fn is_cached_header(name: &str) -> bool {
+    !UNCACHED_HEADERS.contains(&name)
+}
+
+/// A response body and the headers needed to replay it
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+/// Sidecar written next to each body on disk, so the index survives a restart
+#[derive(Serialize, Deserialize)]
+struct DiskMeta {
+    key: String,
+    cid: String,
+    headers: Vec<(String, String)>,
+}
+
+struct MemoryEntry {
+    cid: String,
+    response: CachedResponse,
+}
+
+struct DiskEntry {
+    cid: String,
+    headers: Vec<(String, String)>,
+    size: u64,
+}
+
+struct Tiers {
+    memory: LruCache<String, MemoryEntry>,
+    memory_bytes: u64,
+    disk: LruCache<String, DiskEntry>,
+    disk_bytes: u64,
+}
+
+/// Size-bounded memory and disk LRU cache of immutable content, keyed by CID and path
+pub struct ContentCache {
+    tiers: Mutex<Tiers>,
+    dir: PathBuf,
+    max_memory_bytes: u64,
+    max_disk_bytes: u64,
+    max_entry_bytes: u64,
+    /// Bumped on every purge so disk writes that were in flight don't bring content back
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+fn file_stem(dir: &Path, key: &str) -> PathBuf {
+    dir.join(hex::encode(Sha256::digest(key.as_bytes())))
+}
+
+fn remove_files(stem: &Path) {
+    let _ = fs::remove_file(stem.with_extension("body"));
+    let _ = fs::remove_file(stem.with_extension("json"));
+}
+
+fn write_files(stem: &Path, meta: &DiskMeta, body: &[u8]) -> io::Result<()> {
+    let partial = stem.with_extension("partial");
+    fs::write(&partial, body)?;
+    fs::rename(&partial, stem.with_extension("body"))?;
+    fs::write(stem.with_extension("json"), serde_json::to_vec(meta)?)
+}
+
+/// Read an existing cache directory, oldest entries first
+fn scan(dir: &Path) -> io::Result<Vec<(DiskMeta, u64)>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "partial") {
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+        if path.extension().map_or(true, |ext| ext != "json") {
+            continue;
+        }
+        let body = fs::metadata(path.with_extension("body"));
+        let meta = fs::read(&path).ok().and_then(|meta| serde_json::from_slice::<DiskMeta>(&meta).ok());
+        match (meta, body) {
+            (Some(meta), Ok(body)) => {
+                found.push((body.modified().unwrap_or(SystemTime::UNIX_EPOCH), meta, body.len()));
+            }
+            _ => remove_files(&path.with_extension("")),
+        }
+    }
+    found.sort_by_key(|(modified, _, _)| *modified);
+    Ok(found.into_iter().map(|(_, meta, size)| (meta, size)).collect())
+}
+
+impl ContentCache {
+    /// Open the cache in `dir`, picking up content stored by a previous run
+    pub fn open(dir: &Path, max_memory_bytes: u64, max_disk_bytes: u64, max_entry_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let cache = ContentCache {
+            tiers: Mutex::new(Tiers {
+                memory: LruCache::unbounded(),
+                memory_bytes: 0,
+                disk: LruCache::unbounded(),
+                disk_bytes: 0,
+            }),
+            dir: dir.to_path_buf(),
+            max_memory_bytes,
+            max_disk_bytes,
+            max_entry_bytes,
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        };
+        {
+            let mut tiers = cache.tiers.lock().unwrap();
+            for (meta, size) in scan(dir)? {
+                tiers.disk_bytes += size;
+                tiers.disk.put(meta.key, DiskEntry {
+                    cid: meta.cid,
+                    headers: meta.headers,
+                    size,
+                });
+            }
+            cache.evict(&mut tiers);
+        }
+        Ok(cache)
+    }
+
+    pub fn max_entry_bytes(&self) -> u64 {
+        self.max_entry_bytes
+    }
+
+    /// Drop least recently used content until both tiers fit their budgets
+    fn evict(&self, tiers: &mut Tiers) {
+        while tiers.memory_bytes > self.max_memory_bytes {
+            match tiers.memory.pop_lru() {
+                Some((_, entry)) => tiers.memory_bytes -= entry.response.body.len() as u64,
+                None => break,
+            }
+        }
+        while tiers.disk_bytes > self.max_disk_bytes {
+            match tiers.disk.pop_lru() {
+                Some((key, entry)) => {
+                    tiers.disk_bytes -= entry.size;
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                    remove_files(&file_stem(&self.dir, &key));
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn remember(&self, tiers: &mut Tiers, key: String, cid: String, response: CachedResponse) {
+        let size = response.body.len() as u64;
+        if size > self.max_memory_bytes {
+            return;
+        }
+        if let Some(old) = tiers.memory.put(key, MemoryEntry { cid, response }) {
+            tiers.memory_bytes -= old.response.body.len() as u64;
+        }
+        tiers.memory_bytes += size;
+        self.evict(tiers);
+    }
+
+    /// Look content up in memory, then on disk, promoting disk hits to memory
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let on_disk = {
+            let mut tiers = self.tiers.lock().unwrap();
+            if let Some(entry) = tiers.memory.get(&key.to_string()) {
+                let response = entry.response.clone();
+                // Keep the disk copy from being evicted while memory serves it
+                tiers.disk.get(&key.to_string());
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(response);
+            }
+            tiers
+                .disk
+                .get(&key.to_string())
+                .map(|entry| (entry.cid.clone(), entry.headers.clone()))
+        };
+
+        let (cid, headers) = match on_disk {
+            Some(found) => found,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        let body_path = file_stem(&self.dir, key).with_extension("body");
+        match web::block(move || fs::read(body_path)).await {
+            Ok(body) => {
+                let response = CachedResponse {
+                    headers,
+                    body: Bytes::from(body),
+                };
+                let mut tiers = self.tiers.lock().unwrap();
+                self.remember(&mut tiers, key.to_string(), cid, response.clone());
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(response)
+            }
+            Err(e) => {
+                println!("Error reading cached content {}: {}", key, e);
+                let mut tiers = self.tiers.lock().unwrap();
+                if let Some(entry) = tiers.disk.pop(&key.to_string()) {
+                    tiers.disk_bytes -= entry.size;
+                }
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Store a complete response in memory right away and on disk in the background
+    pub fn insert(self: Arc<Self>, key: String, cid: String, response: CachedResponse) {
+        let size = response.body.len() as u64;
+        if size > self.max_entry_bytes {
+            return;
+        }
+        {
+            let mut tiers = self.tiers.lock().unwrap();
+            self.remember(&mut tiers, key.clone(), cid.clone(), response.clone());
+        }
+        if size > self.max_disk_bytes {
+            return;
+        }
+
+        let generation = self.generation.load(Ordering::Relaxed);
+        let stem = file_stem(&self.dir, &key);
+        let meta = DiskMeta {
+            key,
+            cid,
+            headers: response.headers,
+        };
+        let body = response.body;
+        rt::spawn(async move {
+            let written = web::block(move || write_files(&stem, &meta, &body).map(|_| (stem, meta))).await;
+            match written {
+                Ok((stem, meta)) => {
+                    let mut tiers = self.tiers.lock().unwrap();
+                    if self.generation.load(Ordering::Relaxed) != generation {
+                        remove_files(&stem);
+                        return;
+                    }
+                    let entry = DiskEntry {
+                        cid: meta.cid,
+                        headers: meta.headers,
+                        size,
+                    };
+                    if let Some(old) = tiers.disk.put(meta.key, entry) {
+                        tiers.disk_bytes -= old.size;
+                    }
+                    tiers.disk_bytes += size;
+                    self.evict(&mut tiers);
+                }
+                Err(e) => println!("Error writing cached content: {}", e),
+            }
+        });
+    }
+
+    /// Remove everything cached for `cid`, or the whole cache, returning how many entries went
+    pub fn purge(&self, cid: Option<&str>) -> usize {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        let mut tiers = self.tiers.lock().unwrap();
+        let matches = |entry_cid: &str| cid.map_or(true, |cid| cid == entry_cid);
+
+        let memory_keys: Vec<String> = tiers
+            .memory
+            .iter()
+            .filter(|(_, entry)| matches(&entry.cid))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &memory_keys {
+            if let Some(entry) = tiers.memory.pop(key) {
+                tiers.memory_bytes -= entry.response.body.len() as u64;
+            }
+        }
+
+        let disk_keys: Vec<String> = tiers
+            .disk
+            .iter()
+            .filter(|(_, entry)| matches(&entry.cid))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &disk_keys {
+            if let Some(entry) = tiers.disk.pop(key) {
+                tiers.disk_bytes -= entry.size;
+                remove_files(&file_stem(&self.dir, key));
+            }
+        }
+
+        let mut purged = disk_keys;
+        purged.extend(memory_keys);
+        purged.sort();
+        purged.dedup();
+        purged.len()
+    }
+
+    pub fn status(&self) -> Value {
+        let tiers = self.tiers.lock().unwrap();
+        json!({
+            "hits": self.hits.load(Ordering::Relaxed),
+            "misses": self.misses.load(Ordering::Relaxed),
+            "evictions": self.evictions.load(Ordering::Relaxed),
+            "memory": {
+                "entries": tiers.memory.len(),
+                "bytes": tiers.memory_bytes,
+                "max_bytes": self.max_memory_bytes,
+            },
+            "disk": {
+                "entries": tiers.disk.len(),
+                "bytes": tiers.disk_bytes,
+                "max_bytes": self.max_disk_bytes,
+            },
+            "max_entry_bytes": self.max_entry_bytes,
+        })
+    }
+}
+
+/// What to store once a forwarded response has been read to the end
+pub struct CacheFill {
+    pub cache: Arc<ContentCache>,
+    pub key: String,
+    pub cid: String,
+    pub headers: Vec<(String, String)>,
+    /// The response is only stored if exactly this many bytes arrive
+    pub expected_length: Option<u64>,
+}
+
+/// Passes a response body through, handing it to the cache if it completes within the size limit
+pub struct CachingStream<S> {
+    inner: S,
+    fill: Option<(CacheFill, BytesMut)>,
+}
+
+impl<S> CachingStream<S> {
+    pub fn new(inner: S, fill: Option<CacheFill>) -> Self {
+        CachingStream {
+            inner,
+            fill: fill.map(|fill| (fill, BytesMut::new())),
+        }
+    }
+}
+
+impl<S, E> Stream for CachingStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.inner).poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some((fill, body)) = self.fill.as_mut() {
+                    if (body.len() + chunk.len()) as u64 > fill.cache.max_entry_bytes() {
+                        self.fill = None;
+                    } else {
+                        body.extend_from_slice(chunk);
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.fill = None,
+            Poll::Ready(None) => {
+                if let Some((fill, body)) = self.fill.take() {
+                    if fill.expected_length.is_some_and(|expected| expected != body.len() as u64) {
+                        return polled;
+                    }
+                    let response = CachedResponse {
+                        headers: fill.headers,
+                        body: body.freeze(),
+                    };
+                    fill.cache.insert(fill.key, fill.cid, response);
+                }
+            }
+            Poll::Pending => {}
+        }
+        polled
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{
+        Context,
+        Poll,
+    },
+    time::Duration,
+};
+use actix_service::{
+    Service,
+    Transform,
+};
+use actix_web::{
+    dev::{
+        ServiceRequest,
+        ServiceResponse,
+    },
+    http::{
+        header::{
+            self,
+            HeaderMap,
+            HeaderValue,
+        },
+        Method,
+    },
+    error::InternalError,
+    web,
+    Error,
+    HttpMessage,
+    HttpResponse,
+};
+use futures::{
+    future::{
+        ok,
+        Ready,
+    },
+    Future,
+};
+use super::cache::KeyStatus;
+use super::error::JsonError;
+use super::credentials::{
+    api_key,
+    parse_key,
+};
+use super::middlewares::KeyValidator;
+use super::reload::Live;
+use super::trace::RequestContext;
+
+const ALLOWED_METHODS: &str = "GET, POST, PUT, DELETE, OPTIONS";
+const ALLOWED_HEADERS: &str = "authorization, x-api-key, content-type, x-request-id, traceparent";
+const EXPOSED_HEADERS: &str = "x-request-id, retry-after";
+
+/// Origins browsers may call the proxy from, `*` or an empty list allows any and leaves it to
+/// each key's own `allowed_origins`
+#[derive(Clone)]
+pub struct CorsPolicy {
+    allowed_origins: Vec<String>,
+    max_age: Duration,
+}
+
+impl CorsPolicy {
+    pub fn new(allowed_origins: Vec<String>, max_age: Duration) -> Self {
+        CorsPolicy {
+            allowed_origins,
+            max_age,
+        }
+    }
+
+    /// Parse a comma separated origin list such as `https://app.example.com,http://localhost:3000`
+    pub fn parse(origins: &str, max_age: Duration) -> Self {
+        let allowed_origins = origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        CorsPolicy::new(allowed_origins, max_age)
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins.is_empty()
+            || self.allowed_origins.iter().any(|allowed| allowed == "*" || allowed == origin)
+    }
+}
+
+fn origin(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::ORIGIN).and_then(|value| value.to_str().ok())
+}
+
+fn origin_not_allowed() -> JsonError {
+    JsonError {
+        msg: "Origin is not allowed".to_string(),
+        status: 403,
+        success: false,
+        retry_after: None,
+    }
+}
+
+/// Reject browser requests from origins the key's owner hasn't allowed
+pub fn check_key_origin(status: &KeyStatus, headers: &HeaderMap) -> Result<(), JsonError> {
+    match origin(headers) {
+        Some(origin) if !status.allows_origin(origin) => Err(origin_not_allowed()),
+        _ => Ok(()),
+    }
+}
+
+fn allow_origin(headers: &mut HeaderMap, origin: &HeaderValue) {
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+}
+
+fn expose(headers: &mut HeaderMap, origin: &HeaderValue) {
+    allow_origin(headers, origin);
+    headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(EXPOSED_HEADERS));
+}
+
+/// Answers preflight requests and adds CORS headers to responses for allowed origins
+pub struct Cors(Arc<Live<CorsPolicy>>);
+
+impl Cors {
+    pub fn new(policy: Arc<Live<CorsPolicy>>) -> Self {
+        Cors(policy)
+    }
+}
+
+impl<S, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware {
+            service,
+            policy: self.0.clone(),
+        })
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    policy: Arc<Live<CorsPolicy>>,
+    service: S,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let origin_value = match req.headers().get(header::ORIGIN) {
+            Some(origin) => origin.clone(),
+            None => return Box::pin(self.service.call(req)),
+        };
+        let policy = self.policy.load();
+        if !origin(req.headers()).is_some_and(|origin| policy.allows(origin)) {
+            return Box::pin(async { Err(origin_not_allowed().into()) });
+        }
+
+        let is_preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if is_preflight {
+            let max_age = policy.max_age.as_secs();
+            let apikey = api_key(req.headers(), req.query_string(), req.app_data());
+            let headers = req.headers().clone();
+            let validator = req.app_data::<web::Data<KeyValidator>>().cloned();
+            let context = req.extensions().get::<RequestContext>().cloned();
+            return Box::pin(async move {
+                // Browsers leave credentials off preflights, so the key's own origins can only be
+                // checked here when a client supplies it anyway
+                if let (Some(apikey), Some(validator)) = (apikey?, validator) {
+                    let status = validator.check(&parse_key(&apikey)?, context.as_ref()).await?;
+                    check_key_origin(&status, &headers)?;
+                }
+                let mut res = HttpResponse::NoContent()
+                    .header(header::ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS)
+                    .header(header::ACCESS_CONTROL_ALLOW_HEADERS, ALLOWED_HEADERS)
+                    .header(header::ACCESS_CONTROL_MAX_AGE, max_age.to_string())
+                    .finish();
+                allow_origin(res.headers_mut(), &origin_value);
+                Ok(req.into_response(res.into_body()))
+            });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            match fut.await {
+                Ok(mut res) => {
+                    expose(res.headers_mut(), &origin_value);
+                    Ok(res)
+                }
+                // Errors carry the headers too so browsers can read them
+                Err(e) => {
+                    let mut res = e.as_response_error().error_response();
+                    expose(res.headers_mut(), &origin_value);
+                    Err(InternalError::from_response(e, res).into())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,119 @@
+use actix_web::{
+    http::header::{
+        self,
+        HeaderMap,
+    },
+    web,
+    HttpRequest,
+};
+use uuid::Uuid;
+use super::error::JsonError;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+const API_KEY_PARAM: &str = "api_key";
+
+/// Whether keys are also accepted in the `api_key` query parameter, for gateway links that can't set headers
+pub struct QueryKeys(pub bool);
+
+pub fn unauthorized(msg: &str) -> JsonError {
+    JsonError {
+        msg: msg.to_string(),
+        status: 401,
+        success: false,
+        retry_after: None,
+    }
+}
+
+/// The single value of a header, `Ok(None)` when absent
+fn single_header<'a>(headers: &'a HeaderMap, name: &str) -> Result<Option<&'a str>, JsonError> {
+    let mut values = headers.get_all(name);
+    let value = match values.next() {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    if values.next().is_some() {
+        return Err(unauthorized(&format!("{} header must only be given once", name)));
+    }
+    let value = value
+        .to_str()
+        .map_err(|_| unauthorized(&format!("{} header must be printable ASCII", name)))?
+        .trim();
+    if value.is_empty() {
+        return Err(unauthorized(&format!("{} header is empty", name)));
+    }
+    Ok(Some(value))
+}
+
+/// `Authorization: Bearer <key>`, or the bare key older clients send
+fn authorization_key(headers: &HeaderMap) -> Result<Option<&str>, JsonError> {
+    let value = match single_header(headers, header::AUTHORIZATION.as_str())? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.split_once(' ') {
+        None if value.eq_ignore_ascii_case("bearer") => Err(unauthorized("Bearer credentials must be a single token")),
+        None => Ok(Some(value)),
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            let token = token.trim();
+            if token.is_empty() || token.contains(' ') {
+                Err(unauthorized("Bearer credentials must be a single token"))
+            } else {
+                Ok(Some(token))
+            }
+        }
+        Some(_) => Err(unauthorized("Authorization scheme must be Bearer")),
+    }
+}
+
+/// The API key a request carries in `Authorization`, `X-API-Key` or, when `query_keys` allows it,
+/// `?api_key=`. Several places may carry the same key, but never different ones
+pub fn api_key(headers: &HeaderMap, query: &str, query_keys: Option<&web::Data<QueryKeys>>) -> Result<Option<String>, JsonError> {
+    let mut keys: Vec<String> = Vec::new();
+    if let Some(key) = authorization_key(headers)? {
+        keys.push(key.to_string());
+    }
+    if let Some(key) = single_header(headers, API_KEY_HEADER)? {
+        keys.push(key.to_string());
+    }
+    if query_keys.is_some_and(|query_keys| query_keys.0) {
+        let mut params = url::form_urlencoded::parse(query.as_bytes()).filter(|(name, _)| name == API_KEY_PARAM);
+        if let Some((_, key)) = params.next() {
+            if params.next().is_some() {
+                return Err(unauthorized("api_key must only be given once"));
+            }
+            if key.trim().is_empty() {
+                return Err(unauthorized("api_key is empty"));
+            }
+            keys.push(key.trim().to_string());
+        }
+    }
+    match keys.split_first() {
+        None => Ok(None),
+        Some((key, others)) if others.iter().all(|other| other == key) => Ok(Some(key.clone())),
+        Some(_) => Err(unauthorized("Request carries more than one API key")),
+    }
+}
+
+/// The canonical form of an API key, which is always a UUID
+pub fn parse_key(key: &str) -> Result<String, JsonError> {
+    Uuid::parse_str(key)
+        .map(|key| key.to_hyphenated().to_string())
+        .map_err(|_| unauthorized("APIKey is malformed"))
+}
+
+/// The canonical key of a request `Authorized` already admitted
+pub fn request_key(req: &HttpRequest) -> Option<String> {
+    api_key(req.headers(), req.query_string(), req.app_data())
+        .ok()
+        .flatten()
+        .and_then(|key| parse_key(&key).ok())
+}
+
+/// The query string without any `api_key` parameter, so keys never reach the IPFS node or the logs
+pub fn strip_api_key_param(query: &str) -> String {
+    if !url::form_urlencoded::parse(query.as_bytes()).any(|(name, _)| name == API_KEY_PARAM) {
+        return query.to_string();
+    }
+    let params = url::form_urlencoded::parse(query.as_bytes()).filter(|(name, _)| name != API_KEY_PARAM);
+    url::form_urlencoded::Serializer::new(String::new()).extend_pairs(params).finish()
+}
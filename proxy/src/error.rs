@@ -1,5 +1,8 @@
 use actix_web::{
-    http::StatusCode, 
+    http::{
+        header,
+        StatusCode,
+    },
     HttpResponse, 
     ResponseError
 };
@@ -12,6 +15,7 @@ use std::fmt::{
 
 
 #[derive(thiserror::Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ProxyError {
     #[error("invalid field in BSON document: {0}")]
     InvalidFieldError(#[from] bson::document::ValueAccessError),
@@ -20,6 +24,12 @@ pub enum ProxyError {
         #[from]
         source: mongodb::error::Error,
     },
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("serialization failed: {0}")]
+    SerializationError(#[from] serde_json::Error),
 }
 
 #[derive(Debug, Serialize)]
@@ -27,6 +37,8 @@ pub struct JsonError {
     pub msg: String,
     pub status: u16,
     pub success: bool,
+    #[serde(skip)]
+    pub retry_after: Option<u64>,
 }
 
 impl Display for JsonError {
@@ -38,20 +50,41 @@ impl Display for JsonError {
 
 impl ResponseError for JsonError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(StatusCode::from_u16(self.status).unwrap()).json2(self)
+        let mut res = HttpResponse::build(StatusCode::from_u16(self.status).unwrap());
+        if let Some(retry_after) = self.retry_after {
+            res.header(header::RETRY_AFTER, retry_after.to_string());
+        }
+        res.json2(self)
+    }
+}
+
+impl JsonError {
+    /// A request body over the caller's upload limit
+    pub fn payload_too_large(limit: u64) -> Self {
+        JsonError {
+            msg: format!("Request body exceeds the upload limit of {} bytes", limit),
+            status: 413,
+            success: false,
+            retry_after: None,
+        }
     }
 }
 
 impl From<ProxyError> for JsonError {
     fn from(err: ProxyError) -> Self {
         let status = match err {
-            ProxyError::MongoDBOperationError { source: _ } | ProxyError::InvalidFieldError(_) => 500,
+            ProxyError::MongoDBOperationError { source: _ }
+            | ProxyError::InvalidFieldError(_)
+            | ProxyError::IoError(_)
+            | ProxyError::SerializationError(_) => 500,
+            ProxyError::InvalidQuery(_) => 400,
         };
 
         JsonError {
-            msg: format!("{}", err.to_string()),
-            status: status,
+            msg: err.to_string(),
+            status,
             success: false,
+            retry_after: None,
         }
     }
 }
@@ -0,0 +1,150 @@
+use std::{
+    cell::RefCell,
+    sync::Arc,
+    time::Duration,
+};
+use actix_web::{
+    client::Client,
+    http::{
+        header::{
+            self,
+            HeaderMap,
+        },
+        StatusCode,
+    },
+};
+use rustls::ClientConfig;
+use super::policy::matches_pattern;
+use super::reload::Live;
+use super::tls::{
+    client_builder,
+    client_builder_with_connect_timeout,
+};
+
+/// IPFS API reads that can safely be sent again, retried only when they carry no body
+const IDEMPOTENT_READS: &[&str] = &[
+    "/api/v0/cat",
+    "/api/v0/get",
+    "/api/v0/ls",
+    "/api/v0/version",
+    "/api/v0/block/get",
+    "/api/v0/block/stat",
+    "/api/v0/dag/get",
+    "/api/v0/dag/stat",
+    "/api/v0/pin/ls",
+];
+
+pub fn is_idempotent_read(path: &str) -> bool {
+    IDEMPOTENT_READS.contains(&path)
+}
+
+/// Whether a response means the node itself couldn't serve the call. Kubo answers ordinary
+/// command errors, like `cat` of an unknown CID, with 500, so those don't count against it
+pub fn is_unavailable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// The request body limit that applies to the caller, set by `Authorized` for `forward` to enforce
+#[derive(Clone, Copy, Debug)]
+pub struct UploadLimit(pub u64);
+
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse().ok())
+}
+
+/// Timeouts for forwarded requests whose path matches `path`, where `*` matches any run of characters
+///
+/// `read` covers sending the request body and waiting for the response to start.
+#[derive(Clone, Debug)]
+pub struct RouteTimeouts {
+    pub path: String,
+    pub connect: Duration,
+    pub read: Duration,
+}
+
+impl RouteTimeouts {
+    /// Parse `PATH:CONNECT_SECS:READ_SECS`, e.g. `/api/v0/add:5:600`
+    pub fn parse(route: &str) -> Option<Self> {
+        let mut parts = route.rsplitn(3, ':');
+        let read = parts.next()?.trim().parse().ok()?;
+        let connect = parts.next()?.trim().parse().ok()?;
+        let path = parts.next()?.trim();
+        if path.is_empty() {
+            return None;
+        }
+        Some(RouteTimeouts {
+            path: path.to_string(),
+            connect: Duration::from_secs(connect),
+            read: Duration::from_secs(read),
+        })
+    }
+}
+
+/// Upstream clients for one worker, one per route since connect timeouts live on the connector.
+/// They are rebuilt the first time they're needed after the routes change
+pub struct UpstreamClients {
+    routes: Arc<Live<Vec<RouteTimeouts>>>,
+    tls: Option<Arc<ClientConfig>>,
+    built: RefCell<(Arc<Vec<RouteTimeouts>>, Vec<Client>)>,
+    fallback: Client,
+}
+
+fn route_clients(routes: &[RouteTimeouts], tls: Option<&Arc<ClientConfig>>) -> Vec<Client> {
+    routes
+        .iter()
+        .map(|route| client_builder_with_connect_timeout(tls, route.connect).disable_timeout().finish())
+        .collect()
+}
+
+impl UpstreamClients {
+    pub fn new(routes: Arc<Live<Vec<RouteTimeouts>>>, tls: Option<&Arc<ClientConfig>>) -> Self {
+        let current = routes.load();
+        let clients = route_clients(&current, tls);
+        UpstreamClients {
+            routes,
+            tls: tls.cloned(),
+            built: RefCell::new((current, clients)),
+            fallback: client_builder(tls).disable_timeout().finish(),
+        }
+    }
+
+    /// The client and read timeout for the first route matching `path`, no read timeout otherwise
+    pub fn route(&self, path: &str) -> (Client, Option<Duration>) {
+        let current = self.routes.load();
+        let mut built = self.built.borrow_mut();
+        if !Arc::ptr_eq(&built.0, &current) {
+            let clients = route_clients(&current, self.tls.as_ref());
+            *built = (current, clients);
+        }
+        let (routes, clients) = &*built;
+        match routes.iter().position(|route| matches_pattern(&route.path, path)) {
+            Some(index) => (clients[index].clone(), Some(routes[index].read)),
+            None => (self.fallback.clone(), None),
+        }
+    }
+}
+
+/// How often and how patiently idempotent reads are retried on another attempt
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Full jitter exponential backoff before attempt `attempt + 1`
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::random::<f64>())
+    }
+}
@@ -0,0 +1,174 @@
+use std::net::IpAddr;
+use actix_web::{
+    http::header::{
+        self,
+        HeaderMap,
+        HeaderName,
+        HeaderValue,
+    },
+    HttpRequest,
+};
+use super::credentials::API_KEY_HEADER;
+
+/// Headers that only describe a single connection, RFC 7230 section 6.1
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Remove hop-by-hop headers, including any the `Connection` header names
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// An address or CIDR block such as `10.0.0.0/8`
+struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    fn parse(network: &str) -> Option<Self> {
+        let (addr, prefix) = match network.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (network, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max)?,
+            None => max,
+        };
+        Some(Network { addr, prefix })
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) as u128, u32::from(*ip) as u128, 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(*ip), 128),
+            _ => return false,
+        };
+        let mask = if self.prefix == 0 { 0 } else { u128::MAX << (bits - self.prefix) };
+        net & mask == ip & mask
+    }
+}
+
+/// Peers whose forwarding headers are believed, anyone else's are replaced
+pub struct TrustedProxies(Vec<Network>);
+
+impl TrustedProxies {
+    /// Parse a comma separated list of addresses and CIDR blocks
+    pub fn parse(networks: &str) -> Option<Self> {
+        networks
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(Network::parse)
+            .collect::<Option<Vec<_>>>()
+            .map(TrustedProxies)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// A `Forwarded` node or host, quoted since IPv6 addresses and ports aren't valid tokens
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn joined(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers.get_all(name).filter_map(|value| value.to_str().ok()).collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+/// Prepare headers copied from the client for the IPFS node: drop hop-by-hop headers, the
+/// client's credentials, host and browser origin, and describe the client in `X-Forwarded-*` and `Forwarded`
+pub fn prepare_forwarded(req: &HttpRequest, headers: &mut HeaderMap, trusted: &TrustedProxies) {
+    strip_hop_by_hop(headers);
+    headers.remove(header::AUTHORIZATION);
+    headers.remove(API_KEY_HEADER);
+    headers.remove(header::HOST);
+    // The proxy is the CORS boundary, Kubo refuses cross-origin calls it wasn't configured for
+    headers.remove(header::ORIGIN);
+    headers.remove(header::REFERER);
+
+    let peer = req.head().peer_addr.map(|addr| addr.ip());
+    let trusted = peer.as_ref().is_some_and(|peer| trusted.contains(peer));
+    let proto = if req.app_config().secure() { "https" } else { "http" };
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| req.uri().authority().map(|authority| authority.to_string()));
+
+    let previous = |name: &str| if trusted { joined(req.headers(), name) } else { None };
+    let forwarded_for = previous(X_FORWARDED_FOR);
+    let forwarded_proto = previous(X_FORWARDED_PROTO);
+    let forwarded_host = previous(X_FORWARDED_HOST);
+    let forwarded = previous(header::FORWARDED.as_str());
+    for name in &[X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST, header::FORWARDED.as_str()] {
+        headers.remove(*name);
+    }
+
+    let mut set = |name: &'static str, value: String| {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    };
+    if let Some(peer) = peer {
+        set(X_FORWARDED_FOR, match forwarded_for {
+            Some(previous) => format!("{}, {}", previous, peer),
+            None => peer.to_string(),
+        });
+    }
+    set(X_FORWARDED_PROTO, forwarded_proto.unwrap_or_else(|| proto.to_string()));
+    if let Some(host) = forwarded_host.or_else(|| host.clone()) {
+        set(X_FORWARDED_HOST, host);
+    }
+
+    let mut element = Vec::new();
+    if let Some(peer) = peer {
+        let node = match peer {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => quoted(&format!("[{}]", ip)),
+        };
+        element.push(format!("for={}", node));
+    }
+    if let Some(host) = &host {
+        element.push(format!("host={}", quoted(host)));
+    }
+    element.push(format!("proto={}", proto));
+    set("forwarded", match forwarded {
+        Some(previous) => format!("{}, {}", previous, element.join(";")),
+        None => element.join(";"),
+    });
+}
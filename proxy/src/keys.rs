@@ -0,0 +1,34 @@
+use hmac::{
+    Hmac,
+    Mac,
+    NewMac,
+};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Turns API keys into the identifiers stored in request logs, so the logs never hold
+/// a usable credential. An empty secret is still used as the HMAC key, so anyone holding
+/// a key can recompute its identifier
+#[derive(Clone)]
+pub struct KeyHasher {
+    secret: Vec<u8>,
+}
+
+impl KeyHasher {
+    pub fn new(secret: &[u8]) -> Self {
+        KeyHasher {
+            secret: secret.to_vec(),
+        }
+    }
+
+    /// Hex HMAC-SHA256 of a key, UUIDs are normalized first so any spelling matches
+    pub fn hash(&self, key: &str) -> String {
+        let key = match Uuid::parse_str(key) {
+            Ok(uuid) => uuid.to_hyphenated().to_string(),
+            Err(_) => key.to_string(),
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(key.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
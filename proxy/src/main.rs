@@ -1,36 +1,99 @@
 
+mod access;
+mod breaker;
+mod cache;
+mod config;
+mod content;
+mod cors;
+mod credentials;
 mod error;
+mod forwarding;
+mod headers;
+mod keys;
+mod metrics;
+mod outage;
+mod policy;
 mod processor;
+mod reload;
 mod routes;
 mod middlewares;
+mod sinks;
+mod stats;
+mod streams;
+mod upstream;
+mod writer;
 
-use std::net::ToSocketAddrs;
+use std::{
+    sync::Arc,
+    time::Duration,
+};
 use actix_web::{
     web,
     App,
     HttpServer,
     middleware,
-    client::Client,
 };
 
 use mongodb::{
     self,
     options::ClientOptions,
 };
-use url::Url;
+use service_common::{
+    health,
+    tls,
+    trace,
+};
 use processor::{
     RequestProcessor,
 };
-use middlewares::Authorized;
+use breaker::CircuitBreaker;
+use cache::AuthCache;
+use config::{
+    Args,
+    Config,
+};
+use content::ContentCache;
+use cors::Cors;
+use credentials::QueryKeys;
+use outage::{
+    AuthHealth,
+    OutagePolicy,
+};
+use access::AdminToken;
+use forwarding::UpstreamClients;
+use headers::TrustedProxies;
+use streams::StreamLimiter;
+use keys::KeyHasher;
+use metrics::Metrics;
+use service_common::metrics::RequestMetrics;
+use middlewares::{
+    Authorized,
+    KeyValidator,
+};
+use tls::ReloadingCert;
+use trace::{
+    RequestTracing,
+    TraceExport,
+    Tracer,
+};
+use reload::{
+    Live,
+    Reloadable,
+    Reloader,
+};
+use upstream::UpstreamPool;
+use writer::RequestLogWriter;
 
 struct Container {
     processor: RequestProcessor,
+    log_writer: RequestLogWriter,
 }
 
 impl Container {
-    fn new(processor: RequestProcessor) -> Self {
+    fn new(processor: RequestProcessor, log_writer: RequestLogWriter) -> Self {
         Container {
             processor,
+            log_writer,
         }
     }
 }
@@ -39,61 +102,226 @@ pub struct State {
     container: Container,
 }
 
+/// actix's default access log, with any `api_key` parameter left out of the request line
+fn access_logger() -> middleware::Logger {
+    middleware::Logger::new(r#"%a "%{request_line}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        .custom_request_replace("request_line", |req| {
+            let query = req.query_string();
+            let query = if query.is_empty() {
+                String::new()
+            } else {
+                format!("?{}", credentials::strip_api_key_param(query))
+            };
+            format!("{} {}{} {:?}", req.method(), req.path(), query, req.version())
+        })
+}
+
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let mongodb_address = "mongodb://localhost:27017";
-    let mongodb_name = "secure";
-    let listen_address = "127.0.0.1:5003";
-    let forward_address = "127.0.0.1";
-    let forward_port = 5001;
-    let authentication_address = "127.0.0.1";
-    let authentication_port = 5002;
-
-    let client_options = ClientOptions::parse(mongodb_address).await.unwrap();
-    let client = mongodb::Client::with_options(client_options).unwrap();
-
-    let database = client.database(mongodb_name);
+    let args = Args::parse(config::ENV_PREFIX, &["migrate-key-hashes"], std::env::args().skip(1))
+        .unwrap_or_else(|e| config::exit_with_error(e));
+    let config = Config::load(&args)
+        .and_then(|config| config.validate().map(|_| config))
+        .unwrap_or_else(|e| config::exit_with_error(e));
+    if args.print_config {
+        print!("{}", config.render());
+        return Ok(());
+    }
+
+    let upstream_route_timeouts = Arc::new(Live::new(config.route_timeouts()));
+
+    let client_options = ClientOptions::parse(&config.mongodb_uri)
+        .await
+        .unwrap_or_else(|e| config::exit_with_error(format!("mongodb_uri: {}", e)));
+    let client = mongodb::Client::with_options(client_options)
+        .unwrap_or_else(|e| config::exit_with_error(format!("mongodb_uri: {}", e)));
+
+    let database = client.database(&config.mongodb_database);
     let requests = database.collection("requests");
+    let database = web::Data::new(database);
+    if config.key_hash_secret.is_empty() {
+        println!("key_hash_secret is not set, request logs identify keys by an HMAC-SHA256 with an empty key");
+    }
+    let metrics = web::Data::new(Metrics::default());
+    let processor = RequestProcessor::new(
+        requests,
+        KeyHasher::new(config.key_hash_secret.as_bytes()),
+        metrics.clone().into_inner(),
+    );
+
+    if args.switch("migrate-key-hashes") {
+        let migrated = processor
+            .migrate_key_hashes()
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        println!("Replaced the raw key of {} logged requests with its hash", migrated);
+        return Ok(());
+    }
+
+    let log_writer = RequestLogWriter::start(
+        sinks::from_config(&config, &processor),
+        config.request_log_capacity,
+        config.request_log_batch_size,
+        Duration::from_millis(config.request_log_flush_interval_ms),
+        config.overflow_policy(),
+    );
+
+    let trace_export = TraceExport::parse(&config.trace_exporter, &config.trace_otlp_endpoint)
+        .expect("trace_exporter must be none, stdout or otlp");
+    let tracer = Tracer::start(
+        trace_export,
+        "proxy",
+        config.trace_batch_size,
+        Duration::from_secs(config.trace_flush_interval_secs),
+    );
+
+    // Upstreams and the authentication service given as host:port are reached over TLS when given
+    // a CA bundle, https:// addresses without one are verified against the bundled webpki roots
+    let upstream_tls = config::optional(&config.upstream_ca_bundle).map(|path| {
+        tls::client_config(path.as_ref())
+            .unwrap_or_else(|e| config::exit_with_error(format!("upstream_ca_bundle must be a PEM CA bundle: {}", e)))
+    });
+    let auth_tls = config::optional(&config.auth_ca_bundle).map(|path| {
+        tls::client_config(path.as_ref())
+            .unwrap_or_else(|e| config::exit_with_error(format!("auth_ca_bundle must be a PEM CA bundle: {}", e)))
+    });
+    let scheme = |tls: &Option<_>| if tls.is_some() { "https" } else { "http" };
+
+    let authentication_url = config.auth_url(scheme(&auth_tls)).unwrap_or_else(|e| config::exit_with_error(e));
 
-    let authentication_url = Url::parse(&format!(
-        "http://{}",
-        (authentication_address.to_owned().as_str(), authentication_port)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap(),
-    ))
-    .unwrap();
-
-    let forward_url = Url::parse(&format!(
-        "http://{}",
-        (forward_address, forward_port)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap(),
-    ))
-    .unwrap();
-
-    HttpServer::new(move || {
-        let container = Container::new(RequestProcessor::new(requests.clone()));
+    let upstream_scheme = scheme(&upstream_tls);
+    let upstreams = web::Data::new(UpstreamPool::new(
+        config.upstream_urls(upstream_scheme).unwrap_or_else(|e| config::exit_with_error(e)),
+        config.balancing(),
+        config.upstream_breaker_threshold,
+        Duration::from_secs(config.upstream_breaker_open_secs),
+    ));
+    let upstream_retry = web::Data::new(Live::new(config.retry_policy()));
+    let stream_policy = Arc::new(Live::new(config.stream_policy()));
+    let stream_limiter = web::Data::new(StreamLimiter::new(stream_policy.clone()));
+    let trusted_proxies = web::Data::new(
+        TrustedProxies::parse(&config.trusted_proxies.join(","))
+            .expect("trusted_proxies must be addresses or CIDR blocks"),
+    );
+    let content_cache = web::Data::new(
+        ContentCache::open(
+            config.content_cache_dir.as_ref(),
+            config.content_cache_memory_bytes,
+            config.content_cache_disk_bytes,
+            config.content_cache_max_entry_bytes,
+        )
+        .unwrap_or_else(|e| config::exit_with_error(format!("content_cache_dir {:?}: {}", config.content_cache_dir, e))),
+    );
+    actix_web::rt::spawn(upstreams.clone().into_inner().run_health_checks(
+        Duration::from_secs(config.upstream_probe_interval_secs),
+        Duration::from_secs(config.upstream_probe_timeout_secs),
+        upstream_tls.clone(),
+    ));
+
+    let auth_cache = web::Data::new(AuthCache::new(
+        config.auth_cache_capacity,
+        Duration::from_secs(config.auth_cache_positive_ttl_secs),
+        Duration::from_secs(config.auth_cache_negative_ttl_secs),
+    ));
+    let auth_outage_policy = OutagePolicy::parse(
+        &config.auth_outage_policy,
+        Duration::from_secs(config.auth_outage_grace_period_secs),
+    )
+    .expect("auth_outage_policy must be fail_closed or fail_open");
+    let auth_health = web::Data::new(AuthHealth::new(
+        auth_outage_policy,
+        CircuitBreaker::new(config.auth_breaker_threshold, Duration::from_secs(config.auth_breaker_open_secs)),
+    ));
+
+    let query_keys = web::Data::new(QueryKeys(config.api_key_query_param));
+    let admin_token = web::Data::new(AdminToken(config::optional(&config.admin_token).map(str::to_string)));
+    let cors_policy = Arc::new(Live::new(config.cors_policy()));
+    let endpoint_policy = config.load_endpoint_policy().unwrap_or_else(|e| config::exit_with_error(e));
+    for warning in config.policy_warnings(&endpoint_policy) {
+        println!("{}", warning);
+    }
+    let endpoint_policy = Arc::new(Live::new(endpoint_policy));
+    let reloader = web::Data::new(Reloader::new(args, config.clone(), Reloadable {
+        endpoint_policy: endpoint_policy.clone(),
+        cors_policy: cors_policy.clone(),
+        upstream_retry: upstream_retry.clone().into_inner(),
+        upstream_route_timeouts: upstream_route_timeouts.clone(),
+        stream_policy,
+        upstreams: upstreams.clone().into_inner(),
+        upstream_scheme,
+        log_writer: log_writer.clone(),
+        processor: processor.clone(),
+    }));
+    actix_web::rt::spawn(reloader.clone().into_inner().reload_on_hangup());
+
+    let server_log_writer = log_writer.clone();
+    let server = HttpServer::new(move || {
+        let container = Container::new(processor.clone(), server_log_writer.clone());
+        let validator = KeyValidator::new(
+            &authentication_url,
+            auth_cache.clone().into_inner(),
+            auth_health.clone().into_inner(),
+            metrics.clone().into_inner(),
+            auth_tls.clone(),
+        );
 
         App::new()
-            .wrap(middleware::Logger::default())
+            .wrap(Cors::new(cors_policy.clone()))
+            .wrap(access_logger())
+            .wrap(RequestMetrics::new(&metrics.http, "forward"))
+            .wrap(RequestTracing::new(tracer.clone(), "proxy.request"))
             .data(State { container })
+            .app_data(admin_token.clone())
+            .app_data(query_keys.clone())
+            .app_data(database.clone())
+            .data(validator.clone())
+            .app_data(auth_cache.clone())
+            .app_data(auth_health.clone())
+            .app_data(upstreams.clone())
+            .app_data(upstream_retry.clone())
+            .app_data(stream_limiter.clone())
+            .app_data(content_cache.clone())
+            .app_data(trusted_proxies.clone())
+            .app_data(metrics.clone())
+            .app_data(reloader.clone())
             .service(routes::get_all_requests)
             .service(routes::get_requests_by_key)
+            .service(routes::get_stats)
+            .service(routes::invalidate_auth_cache)
+            .service(routes::get_auth_status)
+            .service(routes::get_upstreams)
+            .service(routes::get_streams)
+            .service(routes::get_config_status)
+            .service(routes::reload_config)
+            .service(routes::get_log_status)
+            .service(routes::get_content_cache)
+            .service(routes::purge_content_cache)
+            .service(routes::purge_content_cache_cid)
+            .service(routes::get_healthz)
+            .service(routes::get_readyz)
+            .service(routes::get_metrics)
             .service(
                 web::scope("/")
-                    .data(Client::new())
-                    .data(forward_url.clone())
-                    .wrap(Authorized::new(&authentication_url))
+                    .data(UpstreamClients::new(upstream_route_timeouts.clone(), upstream_tls.as_ref()))
+                    .wrap(Authorized::new(validator, endpoint_policy.clone()))
                     .default_service(web::route().to(routes::forward)),
             )
-    })
-    .bind(listen_address)?
-    .system_exit()
-    .run()
-    .await
+    });
+    let server = match (config::optional(&config.tls_cert), config::optional(&config.tls_key)) {
+        (Some(cert), Some(key)) => {
+            let cert = ReloadingCert::load(cert.as_ref(), key.as_ref())?;
+            cert.clone().watch(Duration::from_secs(config.tls_reload_interval_secs));
+            server.bind_rustls(&config.listen_address, tls::server_config(cert))?
+        }
+        (None, None) => server.bind(&config.listen_address)?,
+        _ => unreachable!("tls_cert and tls_key are validated together"),
+    };
+    let server = server
+        .system_exit()
+        .run()
+        .await;
+
+    log_writer.shutdown().await;
+    server
 }
@@ -0,0 +1,67 @@
+use std::time::Instant;
+use prometheus::{
+    HistogramOpts,
+    HistogramVec,
+    IntCounterVec,
+    IntGauge,
+    Opts,
+};
+use service_common::metrics::HttpMetrics;
+
+/// Prometheus collectors for the proxy, rendered on `/metrics`
+pub struct Metrics {
+    pub http: HttpMetrics,
+    pub auth_cache_lookups: IntCounterVec,
+    pub auth_service_duration: HistogramVec,
+    pub upstream_errors: IntCounterVec,
+    pub content_cache_lookups: IntCounterVec,
+    pub log_queue_depth: IntGauge,
+    pub mongodb_duration: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let http = HttpMetrics::default();
+        Metrics {
+            auth_cache_lookups: http.register(IntCounterVec::new(
+                Opts::new("auth_cache_lookups_total", "API key validations answered from the cache or not"),
+                &["result"],
+            ).unwrap()),
+            auth_service_duration: http.register(HistogramVec::new(
+                HistogramOpts::new("auth_service_request_duration_seconds", "Calls to the authentication service"),
+                &["outcome"],
+            ).unwrap()),
+            upstream_errors: http.register(IntCounterVec::new(
+                Opts::new("upstream_errors_total", "Failed calls to IPFS upstreams"),
+                &["upstream", "kind"],
+            ).unwrap()),
+            content_cache_lookups: http.register(IntCounterVec::new(
+                Opts::new("content_cache_lookups_total", "Immutable content reads answered from the cache or not"),
+                &["result"],
+            ).unwrap()),
+            log_queue_depth: http.register(
+                IntGauge::new("request_log_queue_depth", "Request logs waiting to be written").unwrap(),
+            ),
+            mongodb_duration: http.register(HistogramVec::new(
+                HistogramOpts::new("mongodb_operation_duration_seconds", "MongoDB operations"),
+                &["operation", "outcome"],
+            ).unwrap()),
+            http,
+        }
+    }
+}
+
+impl Metrics {
+    /// Record how long a MongoDB operation took and whether it failed
+    pub fn observe_mongodb<T, E>(&self, operation: &str, started_at: Instant, result: &Result<T, E>) {
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        self.mongodb_duration
+            .with_label_values(&[operation, outcome])
+            .observe(started_at.elapsed().as_secs_f64());
+    }
+
+    /// Everything registered, in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.http.render()
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    cell::RefCell,
     pin::Pin,
     rc::Rc,
     task::{
@@ -18,7 +19,13 @@ use actix_web::{
     },
     error, 
     Error,
+    HttpMessage,
 };
+use std::{
+    sync::Arc,
+    time::Instant,
+};
+use rustls::ClientConfig;
 use url::Url;
 use actix_service::{
     Service, 
@@ -31,31 +38,184 @@ use futures::{
     },
     Future,
 };
+use super::cache::{
+    AuthCache,
+    KeyStatus,
+    Validation,
+};
+use super::cors::check_key_origin;
+use super::error::JsonError;
+use super::forwarding::{
+    content_length,
+    UploadLimit,
+};
+use super::credentials::{
+    api_key,
+    parse_key,
+    unauthorized,
+};
+use super::metrics::Metrics;
+use super::policy::EndpointPolicy;
+use super::outage::AuthHealth;
+use super::reload::Live;
+use super::tls::client_builder;
+use super::trace::RequestContext;
 use super::processor::ApiKeyResponse;
 
-pub struct Authorized(Rc<Inner>);
-
+pub struct Authorized {
+    validator: Rc<KeyValidator>,
+    policy: Arc<Live<EndpointPolicy>>,
+}
 
-struct Inner {
+/// Validates API keys against the authentication service, going through the shared cache
+#[derive(Clone)]
+pub struct KeyValidator {
     client: Client,
     auth_url: Url,
+    cache: Arc<AuthCache>,
+    health: Arc<AuthHealth>,
+    metrics: Arc<Metrics>,
+}
+
+impl KeyValidator {
+    pub fn new(
+        auth_url: &Url,
+        cache: Arc<AuthCache>,
+        health: Arc<AuthHealth>,
+        metrics: Arc<Metrics>,
+        tls: Option<Arc<ClientConfig>>,
+    ) -> Self {
+        KeyValidator {
+            client: client_builder(tls.as_ref()).finish(),
+            auth_url: auth_url.clone(),
+            cache,
+            health,
+            metrics,
+        }
+    }
+
+    /// Whether the authentication service answers its liveness check
+    pub async fn ping(&self) -> Result<(), String> {
+        let mut health_url = self.auth_url.clone();
+        health_url.set_path("/healthz");
+        let res = self.client.get(health_url.as_str()).send().await.map_err(|e| e.to_string())?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("authentication service returned {}", res.status()))
+        }
+    }
+
+    /// Ask the authentication service about a key, `Err` means the service itself failed
+    async fn lookup(&self, apikey: &str, context: Option<&RequestContext>) -> Result<KeyStatus, String> {
+        let mut auth_url = self.auth_url.clone();
+        auth_url.set_path(&format!("/keys/{}", apikey));
+
+        let mut request = self.client.get(auth_url.as_str());
+        let mut span = context.map(|context| {
+            let span = context.span.client_span("auth.lookup");
+            for (name, value) in context.propagation_headers(&span).iter() {
+                request.headers_mut().insert(
+                    header::HeaderName::from_static(name),
+                    header::HeaderValue::from_str(value).unwrap(),
+                );
+            }
+            span
+        });
+        let mut res = request
+            .send()
+            .await
+            .map_err(|e| {
+                if let Some(span) = span.as_mut() {
+                    span.set_error();
+                }
+                e.to_string()
+            })?;
+        if let Some(span) = span.as_mut() {
+            span.set_attribute("http.status_code", res.status().as_u16());
+            if res.status().is_server_error() {
+                span.set_error();
+            }
+        }
+        if res.status().is_server_error() {
+            return Err(format!("authentication service returned {}", res.status()));
+        }
+        if res.status() != StatusCode::OK {
+            return Ok(KeyStatus::rejected(Validation::Invalid));
+        }
+
+        let apikey_res: ApiKeyResponse = res.json().await.map_err(|e| e.to_string())?;
+        if apikey_res.payload.enabled {
+            Ok(KeyStatus {
+                validation: Validation::Valid,
+                allowed_origins: apikey_res.payload.allowed_origins.map(Arc::new),
+                scope: apikey_res.payload.scope.map(Arc::from),
+                max_upload_bytes: apikey_res.payload.max_upload_bytes,
+            })
+        } else {
+            Ok(KeyStatus::rejected(Validation::Disabled))
+        }
+    }
+
+    /// Succeeds with the key's status if it may be used, applying the outage policy when the
+    /// service is down. `context` links the authentication service call to the request being handled
+    pub async fn check(&self, apikey: &str, context: Option<&RequestContext>) -> Result<KeyStatus, Error> {
+        let cached = self.cache.get(apikey);
+        let cache_result = if cached.is_some() { "hit" } else { "miss" };
+        self.metrics.auth_cache_lookups.with_label_values(&[cache_result]).inc();
+
+        let status = match cached {
+            Some(status) => status,
+            None => {
+                let result = if let Some(attempt) = self.health.breaker.attempt() {
+                    let started_at = Instant::now();
+                    let result = self.lookup(apikey, context).await;
+                    let outcome = if result.is_ok() { "ok" } else { "error" };
+                    self.metrics.auth_service_duration
+                        .with_label_values(&[outcome])
+                        .observe(started_at.elapsed().as_secs_f64());
+                    match result {
+                        Ok(_) => attempt.record_success(),
+                        Err(ref e) => {
+                            println!("Error validating APIKey: {}", e);
+                            attempt.record_failure();
+                        }
+                    }
+                    result.ok()
+                } else {
+                    None
+                };
+
+                match result {
+                    Some(status) => {
+                        self.cache.insert(apikey, status.clone());
+                        status
+                    }
+                    None => self.health.on_outage(&self.cache, apikey)?,
+                }
+            }
+        };
+
+        match status.validation {
+            Validation::Valid => Ok(status),
+            Validation::Disabled => Err(error::ErrorUnauthorized("APIKey is disabled")),
+            Validation::Invalid => Err(error::ErrorUnauthorized("APIKey could not be validated")),
+        }
+    }
 }
 
 impl Authorized {
-    pub fn new(auth_url: &Url) -> Authorized {
-        let client = Client::new();
-        let new_url = auth_url.clone();
-
-        Authorized(Rc::new(Inner {
-            client: client,
-            auth_url: new_url,
-        }))
+    pub fn new(validator: KeyValidator, policy: Arc<Live<EndpointPolicy>>) -> Authorized {
+        Authorized {
+            validator: Rc::new(validator),
+            policy,
+        }
     }
 }
 
 impl<S, B> Transform<S> for Authorized
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -68,20 +228,22 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ok(AuthorizedMiddleware {
-            service,
-            inner: self.0.clone(),
+            service: Rc::new(RefCell::new(service)),
+            inner: self.validator.clone(),
+            policy: self.policy.clone(),
         })
     }
 }
 
 pub struct AuthorizedMiddleware<S> {
-    inner: Rc<Inner>,
-    service: S,
+    inner: Rc<KeyValidator>,
+    policy: Arc<Live<EndpointPolicy>>,
+    service: Rc<RefCell<S>>,
 }
 
 impl<S, B> Service for AuthorizedMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -91,38 +253,31 @@ where
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+        self.service.borrow_mut().poll_ready(cx)
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let headers = req.headers().clone();
-        let fut = self.service.call(req);
-        let mut full_auth_url = self.inner.auth_url.clone();
-        let client = self.inner.client.clone();
+        let service = self.service.clone();
+        let validator = self.inner.clone();
+        let policy = self.policy.load();
 
         Box::pin(async move {
-            let header = headers
-                .get(header::AUTHORIZATION)
-                .map(|h| h.to_str().unwrap());
-            if let Some(apikey) = header {
-                full_auth_url.set_path(&format!("/apikeys/{}", apikey));
-
-                let auth_fut = client.get(full_auth_url.as_str()).send();
+            let apikey = api_key(req.headers(), req.query_string(), req.app_data())?
+                .ok_or_else(|| unauthorized("APIKey is required"))?;
+            let context = req.extensions().get::<RequestContext>().cloned();
+            let status = validator.check(&parse_key(&apikey)?, context.as_ref()).await?;
+            check_key_origin(&status, req.headers())?;
+            policy.check(req.method(), req.path(), req.query_string(), status.scope.as_deref())?;
 
-                let mut res = auth_fut.await?;
-                if res.status() != StatusCode::OK {
-                    return Err(error::ErrorUnauthorized("APIKey could not be validated"));
+            // Refuse a declared body over the limit before any of it is read, forward enforces it on the stream
+            if let Some(limit) = status.max_upload_bytes.or_else(|| policy.upload_limit(status.scope.as_deref())) {
+                if content_length(req.headers()).is_some_and(|length| length > limit) {
+                    return Err(JsonError::payload_too_large(limit).into());
                 }
-
-                let apikey_res: ApiKeyResponse = res.json().await?;
-                if apikey_res.payload.disabled == true {
-                    return Err(error::ErrorUnauthorized("APIKey is disabled"));
-                }
-
-                Ok(fut.await?)
-            } else {
-                Err(error::ErrorUnauthorized("APIKey is required"))
+                req.extensions_mut().insert(UploadLimit(limit));
             }
+            let fut = service.borrow_mut().call(req);
+            fut.await
         })
     }
 }
@@ -0,0 +1,95 @@
+use std::{
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    time::Duration,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use super::breaker::CircuitBreaker;
+use super::cache::{
+    AuthCache,
+    KeyStatus,
+};
+use super::error::JsonError;
+
+/// What to do with a request when the authentication service cannot be reached
+#[derive(Clone, Copy, Debug)]
+pub enum OutagePolicy {
+    /// Reject every request whose key is not freshly cached
+    FailClosed,
+    /// Admit keys that were validated less than `grace_period` ago
+    FailOpen { grace_period: Duration },
+}
+
+impl OutagePolicy {
+    /// Parse `fail_closed` or `fail_open`
+    pub fn parse(mode: &str, grace_period: Duration) -> Option<Self> {
+        match mode {
+            "fail_closed" => Some(OutagePolicy::FailClosed),
+            "fail_open" => Some(OutagePolicy::FailOpen { grace_period }),
+            _ => None,
+        }
+    }
+}
+
+/// Tracks the authentication service's health and applies the outage policy
+pub struct AuthHealth {
+    pub breaker: CircuitBreaker,
+    policy: OutagePolicy,
+    failed_open: AtomicU64,
+    failed_closed: AtomicU64,
+}
+
+impl AuthHealth {
+    pub fn new(policy: OutagePolicy, breaker: CircuitBreaker) -> Self {
+        AuthHealth {
+            breaker,
+            policy,
+            failed_open: AtomicU64::new(0),
+            failed_closed: AtomicU64::new(0),
+        }
+    }
+
+    /// Decide on a key the authentication service could not validate
+    pub fn on_outage(&self, cache: &AuthCache, apikey: &str) -> Result<KeyStatus, JsonError> {
+        if let OutagePolicy::FailOpen { grace_period } = self.policy {
+            if let Some(status) = cache.valid_within(apikey, grace_period) {
+                self.failed_open.fetch_add(1, Ordering::Relaxed);
+                println!("Authentication service unavailable, admitting recently validated key");
+                return Ok(status);
+            }
+        }
+
+        self.failed_closed.fetch_add(1, Ordering::Relaxed);
+        println!("Authentication service unavailable, rejecting request");
+        let retry_after = self.breaker.retry_after().as_secs().max(1);
+        Err(JsonError {
+            msg: "Authentication service unavailable".to_string(),
+            status: 503,
+            success: false,
+            retry_after: Some(retry_after),
+        })
+    }
+
+    pub fn status(&self) -> Value {
+        let policy = match self.policy {
+            OutagePolicy::FailClosed => json!({ "mode": "fail_closed" }),
+            OutagePolicy::FailOpen { grace_period } => json!({
+                "mode": "fail_open",
+                "grace_period_secs": grace_period.as_secs(),
+            }),
+        };
+        json!({
+            "policy": policy,
+            "breaker": self.breaker.state(),
+            "consecutive_failures": self.breaker.consecutive_failures(),
+            "retry_after_secs": self.breaker.retry_after().as_secs(),
+            "failed_open": self.failed_open.load(Ordering::Relaxed),
+            "failed_closed": self.failed_closed.load(Ordering::Relaxed),
+        })
+    }
+}
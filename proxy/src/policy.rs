@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::Path,
+};
+use actix_web::http::Method;
+use serde::Deserialize;
+use super::error::JsonError;
+use super::streams::{
+    LONG_LIVED_PATHS,
+    POLLING_PATHS,
+};
+
+/// IPFS API endpoints exposed when no policy file is given, read and write but no node administration
+const DEFAULT_ALLOWED_PATHS: &[&str] = &[
+    "/api/v0/add",
+    "/api/v0/cat",
+    "/api/v0/get",
+    "/api/v0/ls",
+    "/api/v0/version",
+    "/api/v0/block/get",
+    "/api/v0/block/put",
+    "/api/v0/block/stat",
+    "/api/v0/dag/get",
+    "/api/v0/dag/put",
+    "/api/v0/dag/stat",
+    "/api/v0/pin/add",
+    "/api/v0/pin/ls",
+    "/api/v0/pin/rm",
+];
+
+/// Gateway-style reads of immutable content, which the content cache serves
+const DEFAULT_READ_PATHS: &[&str] = &[
+    "/ipfs/*",
+];
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+/// Matches requests by path pattern, method and query parameters, all of which must match
+///
+/// `*` in a pattern matches any run of characters, so `/api/v0/key/*` covers every key command.
+/// A query parameter pattern of `*` only requires the parameter to be present.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub effect: Effect,
+    pub path: String,
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    #[serde(default)]
+    pub query: HashMap<String, String>,
+}
+
+/// Rules tried before the global ones for keys assigned to a scope
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScopePolicy {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub default: Option<Effect>,
+    /// Largest request body keys in the scope may send, the policy-wide limit when unset
+    #[serde(default)]
+    pub max_upload_bytes: Option<u64>,
+}
+
+/// Which IPFS API endpoints the proxy forwards, the first matching rule decides
+#[derive(Deserialize, Debug, Clone)]
+pub struct EndpointPolicy {
+    #[serde(default = "deny")]
+    pub default: Effect,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub scopes: HashMap<String, ScopePolicy>,
+    /// Largest request body for keys without a limit of their own, unlimited when unset
+    #[serde(default)]
+    pub max_upload_bytes: Option<u64>,
+}
+
+fn deny() -> Effect {
+    Effect::Deny
+}
+
+/// Match `value` against a pattern where `*` stands for any run of characters
+pub fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(index) => rest = &rest[index + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.len() >= last.len() && rest.ends_with(last)
+        }
+    }
+}
+
+/// Decode the path and drop empty segments the way the IPFS API will see it, so
+/// `/api/v0//%73hutdown/` can't slip past a rule for `/api/v0/shutdown`
+pub fn normalize_path(path: &str) -> String {
+    let decoded = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+    let segments: Vec<&str> = decoded.split('/').filter(|segment| !segment.is_empty()).collect();
+    format!("/{}", segments.join("/"))
+}
+
+impl Rule {
+    fn matches(&self, method: &Method, path: &str, query: &[(String, String)]) -> bool {
+        if !matches_pattern(&self.path, path) {
+            return false;
+        }
+        if let Some(methods) = &self.methods {
+            if !methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method.as_str())) {
+                return false;
+            }
+        }
+        self.query.iter().all(|(name, pattern)| {
+            query.iter().any(|(key, value)| key == name && matches_pattern(pattern, value))
+        })
+    }
+}
+
+fn allow<'a>(paths: &'a [&str], methods: &'a [&str]) -> impl Iterator<Item = Rule> + 'a {
+    paths.iter().map(move |path| Rule {
+        effect: Effect::Allow,
+        path: path.to_string(),
+        methods: Some(methods.iter().map(|method| method.to_string()).collect()),
+        query: HashMap::new(),
+    })
+}
+
+impl Default for EndpointPolicy {
+    fn default() -> Self {
+        EndpointPolicy {
+            default: Effect::Deny,
+            rules: allow(DEFAULT_ALLOWED_PATHS, &["POST"])
+                .chain(allow(DEFAULT_READ_PATHS, &["GET", "HEAD"]))
+                // GET too, so `stream_event_source` works without a policy file
+                .chain(allow(LONG_LIVED_PATHS, &["GET", "POST"]))
+                .chain(allow(POLLING_PATHS, &["GET", "POST"]))
+                .collect(),
+            scopes: HashMap::new(),
+            max_upload_bytes: None,
+        }
+    }
+}
+
+impl EndpointPolicy {
+    /// Read a JSON policy file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        serde_json::from_slice(&fs::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn effect(&self, method: &Method, path: &str, query: &str, scope: Option<&str>) -> Effect {
+        let path = normalize_path(path);
+        let query: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        let scope = scope.and_then(|scope| self.scopes.get(scope));
+        let scope_rules = scope.map(|scope| scope.rules.as_slice()).unwrap_or_default();
+        scope_rules
+            .iter()
+            .chain(&self.rules)
+            .find(|rule| rule.matches(method, &path, &query))
+            .map(|rule| rule.effect)
+            .or_else(|| scope.and_then(|scope| scope.default))
+            .unwrap_or(self.default)
+    }
+
+    /// Whether keys without a scope may make this request
+    pub fn allows(&self, method: &Method, path: &str, query: &str) -> bool {
+        self.effect(method, path, query, None) == Effect::Allow
+    }
+
+    /// The request body limit for keys in `scope` that don't carry their own
+    pub fn upload_limit(&self, scope: Option<&str>) -> Option<u64> {
+        scope
+            .and_then(|scope| self.scopes.get(scope))
+            .and_then(|scope| scope.max_upload_bytes)
+            .or(self.max_upload_bytes)
+    }
+
+    /// Reject requests the policy doesn't allow for a key in `scope`
+    pub fn check(&self, method: &Method, path: &str, query: &str, scope: Option<&str>) -> Result<(), JsonError> {
+        match self.effect(method, path, query, scope) {
+            Effect::Allow => Ok(()),
+            Effect::Deny => Err(JsonError {
+                msg: format!("{} {} is not allowed through the proxy", method, path),
+                status: 403,
+                success: false,
+                retry_after: None,
+            }),
+        }
+    }
+}
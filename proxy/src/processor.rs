@@ -4,7 +4,9 @@ use actix_web::{
 };
 use bson::{
     document::ValueAccessError, 
-    Document
+    oid::ObjectId,
+    Document,
+    Regex,
 };
 use chrono::{
     prelude::*,
@@ -12,149 +14,447 @@ use chrono::{
 };
 use serde::{
     Deserialize, 
-    Serialize
+    Serialize,
 };
-use uuid::{
-    Error, 
-    Uuid
+use uuid::Uuid;
+use std::{
+    cell::Cell,
+    rc::Rc,
+    sync::Arc,
+    time::Instant,
 };
 use futures::StreamExt;
 use mongodb::{
-    results::InsertOneResult,
+    options::{
+        AggregateOptions,
+        FindOptions,
+    },
+    results::InsertManyResult,
     bson::doc, 
     bson::Bson, 
     Collection
 };
+use super::credentials::{
+    request_key,
+    strip_api_key_param,
+};
 use super::error::ProxyError;
+use super::keys::KeyHasher;
+use super::metrics::Metrics;
+use super::trace::RequestContext;
+use super::stats::{
+    StatsQuery,
+    UsageStats,
+};
+use super::writer::RequestLogWriter;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request {
     #[serde(rename = "_id")]
     id: String,
+    request_id: Option<String>,
+    trace_id: Option<String>,
     method: String,
     path: String,
-    authorization: Option<Uuid>,
+    query: Option<String>,
+    key_hash: Option<String>,
+    client_ip: Option<String>,
+    user_agent: Option<String>,
+    upstream: Option<String>,
+    status: Option<i32>,
+    upstream_latency_ms: Option<i64>,
+    duration_ms: Option<i64>,
+    request_bytes: Option<i64>,
+    response_bytes: Option<i64>,
     created_at: DateTime<Utc>,
 }
 
 impl Request {
+    /// Records written before outcomes were logged only carry method, path and key
     pub fn from_bson_document(doc: &Document) -> Result<Self, ValueAccessError> {
-        let authorization = match doc.is_null("authorization") {
-            true => None,
-            false => {
-                let key = doc.get_str("authorization")?;
-                Some(Uuid::parse_str(key).expect("Uuid parse failed"))
-            }
-        };
+        let optional_str = |key: &str| doc.get_str(key).ok().map(|value| value.to_string());
         Ok(Request {
             id: doc.get_object_id("_id")?.to_hex(),
+            request_id: optional_str("request_id"),
+            trace_id: optional_str("trace_id"),
             method: doc.get_str("method")?.to_string(),
             path: doc.get_str("path")?.to_string(),
-            authorization: authorization,
+            query: optional_str("query"),
+            key_hash: optional_str("key_hash"),
+            client_ip: optional_str("client_ip"),
+            user_agent: optional_str("user_agent"),
+            upstream: optional_str("upstream"),
+            status: doc.get_i32("status").ok(),
+            upstream_latency_ms: doc.get_i64("upstream_latency_ms").ok(),
+            duration_ms: doc.get_i64("duration_ms").ok(),
+            request_bytes: doc.get_i64("request_bytes").ok(),
+            response_bytes: doc.get_i64("response_bytes").ok(),
             created_at: *doc.get_datetime("created_at")?,
         })
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn direction(&self) -> i32 {
+        match self {
+            SortOrder::Asc => 1,
+            SortOrder::Desc => -1,
+        }
+    }
+}
+
+/// Filters and paging for listing Requests, taken from the query string
+#[derive(Deserialize, Debug, Default)]
+pub struct RequestQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub method: Option<String>,
+    pub path_prefix: Option<String>,
+    pub status: Option<u16>,
+    pub client_ip: Option<String>,
+    /// `_id` of the last Request of the previous page
+    pub cursor: Option<String>,
+    pub order: Option<SortOrder>,
+    pub limit: Option<i64>,
+}
+
+impl RequestQuery {
+    fn to_filter(&self, key_hash: Option<String>) -> Result<Document, ProxyError> {
+        let mut filter = Document::new();
+        if let Some(key_hash) = key_hash {
+            filter.insert("key_hash", key_hash);
+        }
+        let mut created_at = Document::new();
+        if let Some(from) = self.from {
+            created_at.insert("$gte", from);
+        }
+        if let Some(to) = self.to {
+            created_at.insert("$lt", to);
+        }
+        if !created_at.is_empty() {
+            filter.insert("created_at", created_at);
+        }
+        if let Some(method) = &self.method {
+            filter.insert("method", method.to_uppercase());
+        }
+        if let Some(path_prefix) = &self.path_prefix {
+            filter.insert("path", Regex {
+                pattern: format!("^{}", escape_regex(path_prefix)),
+                options: String::new(),
+            });
+        }
+        if let Some(status) = self.status {
+            filter.insert("status", i32::from(status));
+        }
+        if let Some(client_ip) = &self.client_ip {
+            filter.insert("client_ip", client_ip.clone());
+        }
+        if let Some(cursor) = &self.cursor {
+            let id = ObjectId::with_string(cursor)
+                .map_err(|_| ProxyError::InvalidQuery(format!("invalid cursor: {}", cursor)))?;
+            let comparison = match self.order.unwrap_or(SortOrder::Desc) {
+                SortOrder::Asc => "$gt",
+                SortOrder::Desc => "$lt",
+            };
+            filter.insert("_id", doc! { comparison: id });
+        }
+        Ok(filter)
+    }
+}
+
+fn escape_regex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub struct RequestPage {
+    pub requests: Vec<Request>,
+    pub next_cursor: Option<String>,
+}
+
+/// A proxied call, filled in as the request goes through and written once it completes
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NewRequest {
+    /// The `X-Request-Id` echoed to the client
+    pub request_id: String,
+    pub trace_id: Option<String>,
     pub method: String,
     pub path: String,
-    pub authorization: Option<Uuid>,
+    pub query: Option<String>,
+    /// `KeyHasher` output for the caller's API key, never the key itself
+    pub key_hash: Option<String>,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub upstream: Option<String>,
+    pub status: Option<u16>,
+    pub upstream_latency_ms: Option<u64>,
+    pub duration_ms: Option<u64>,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub created_at: DateTime<Utc>,
 }
 
 impl NewRequest {
-    pub fn from_http_request(req: &HttpRequest) -> Result<Self, Error> {
-        let auth_header = req
+    pub fn into_bson_document(self) -> Document {
+        doc! {
+            "request_id": self.request_id,
+            "trace_id": optional(self.trace_id),
+            "method": self.method,
+            "path": self.path,
+            "query": optional(self.query),
+            "key_hash": optional(self.key_hash),
+            "client_ip": optional(self.client_ip),
+            "user_agent": optional(self.user_agent),
+            "upstream": optional(self.upstream),
+            "status": optional(self.status.map(i32::from)),
+            "upstream_latency_ms": optional(self.upstream_latency_ms.map(|ms| ms as i64)),
+            "duration_ms": optional(self.duration_ms.map(|ms| ms as i64)),
+            "request_bytes": self.request_bytes as i64,
+            "response_bytes": self.response_bytes as i64,
+            "created_at": self.created_at,
+        }
+    }
+
+    /// The record for a request that passed authorization, keys that can't be read aren't logged
+    pub fn from_http_request(req: &HttpRequest, hasher: &KeyHasher) -> Self {
+        let key_hash = request_key(req).map(|key| hasher.hash(key.as_str()));
+        let user_agent = req
             .headers()
-            .get(header::AUTHORIZATION)
-            .map(|h| h.to_str().unwrap());
-        let authorization = match auth_header {
-            Some(key) => Some(Uuid::parse_str(key)?),
-            None => None,
-        };
-        Ok(NewRequest {
+            .get(header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string());
+        let context = req.extensions().get::<RequestContext>().cloned();
+        NewRequest {
+            request_id: context
+                .as_ref()
+                .map(|context| context.request_id.clone())
+                .unwrap_or_else(|| Uuid::new_v4().to_hyphenated().to_string()),
+            trace_id: context.map(|context| context.span.trace_id().to_string()),
             method: req.method().as_str().to_string(),
             path: req.path().to_string(),
-            authorization: authorization,
-        })
+            query: req.uri().query().map(strip_api_key_param),
+            key_hash,
+            client_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+            user_agent,
+            upstream: None,
+            status: None,
+            upstream_latency_ms: None,
+            duration_ms: None,
+            request_bytes: 0,
+            response_bytes: 0,
+            created_at: Utc::now(),
+        }
     }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct ApiKey {
-    #[serde(rename = "_id")]
-    id: String,
-    pub key: Uuid,
-    pub disabled: bool,
-    created_at: DateTime<Utc>,
-    updated_at: DateTime<Utc>,
+    pub enabled: bool,
+    #[serde(default)]
+    pub allowed_origins: Option<Vec<String>>,
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub max_upload_bytes: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct ApiKeyResponse {
-    pub status: u16,
-    pub success: bool,
     pub payload: ApiKey,
 }
 
+/// Writes its request record when dropped, which for a forwarded call is once the
+/// response body has been sent or the client went away
+pub struct PendingRequest {
+    request: Option<NewRequest>,
+    started_at: Instant,
+    request_bytes: Rc<Cell<u64>>,
+    writer: RequestLogWriter,
+}
+
+impl PendingRequest {
+    pub fn new(request: NewRequest, writer: RequestLogWriter) -> Self {
+        PendingRequest {
+            request: Some(request),
+            started_at: Instant::now(),
+            request_bytes: Rc::new(Cell::new(0)),
+            writer,
+        }
+    }
+
+    fn request(&mut self) -> &mut NewRequest {
+        self.request.as_mut().unwrap()
+    }
+
+    /// Shared counter for the request body, which is consumed by the upstream call
+    pub fn request_bytes(&self) -> Rc<Cell<u64>> {
+        self.request_bytes.clone()
+    }
+
+    pub fn set_upstream(&mut self, upstream: &str) {
+        self.request().upstream = Some(upstream.to_string());
+    }
+
+    /// Record the response status and the time spent waiting for it
+    pub fn set_response(&mut self, status: u16) {
+        let latency = self.started_at.elapsed().as_millis() as u64;
+        let request = self.request();
+        request.status = Some(status);
+        request.upstream_latency_ms = Some(latency);
+    }
+
+    pub fn add_response_bytes(&mut self, bytes: usize) {
+        self.request().response_bytes += bytes as u64;
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        let mut request = self.request.take().unwrap();
+        request.duration_ms = Some(self.started_at.elapsed().as_millis() as u64);
+        request.request_bytes = self.request_bytes.get();
+        self.writer.enqueue(request);
+    }
+}
+
+fn optional<T: Into<Bson>>(value: Option<T>) -> Bson {
+    value.map(Into::into).unwrap_or(Bson::Null)
+}
+
 #[derive(Clone)]
 pub struct RequestProcessor {
     collection: Collection,
+    hasher: KeyHasher,
+    metrics: Arc<Metrics>,
 }
 
 
 impl RequestProcessor {
-    pub fn new(collection: Collection) -> Self {
+    pub fn new(collection: Collection, hasher: KeyHasher, metrics: Arc<Metrics>) -> Self {
         RequestProcessor { 
-            collection 
-        }
-    }
-
-    /// Create a new entry for a Request
-    pub async fn create(&self, req: NewRequest) -> Result<InsertOneResult, ProxyError> {
-        let document = match req.authorization {
-            Some(a) => {
-                doc! {
-                    "method": req.method.clone(),
-                    "path": req.path.clone(),
-                    "authorization": a.to_hyphenated().to_string(),
-                    "created_at": Utc::now(),
-                }
-            }
-            None => {
-                doc! {
-                    "method": req.method.clone(),
-                    "path": req.path.clone(),
-                    "authorization": Bson::Null,
-                    "created_at": Utc::now(),
-                }
-            }
+            collection,
+            hasher,
+            metrics,
+        }
+    }
+
+    pub fn hasher(&self) -> &KeyHasher {
+        &self.hasher
+    }
+
+    /// Create entries for a batch of Requests
+    pub async fn create_many(&self, reqs: Vec<NewRequest>) -> Result<InsertManyResult, ProxyError> {
+        let documents = reqs.into_iter().map(NewRequest::into_bson_document);
+        let started_at = Instant::now();
+        let result = self.collection.insert_many(documents, None).await;
+        self.metrics.observe_mongodb("insert_many", started_at, &result);
+        Ok(result?)
+    }
+
+    /// Find Requests matching a query, optionally only those made with `key`.
+    /// Results are ordered by `_id` and fetched one page at a time
+    pub async fn find(&self, key: Option<&str>, query: &RequestQuery) -> Result<RequestPage, ProxyError> {
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let order = query.order.unwrap_or(SortOrder::Desc);
+        let filter = query.to_filter(key.map(|key| self.hasher.hash(key)))?;
+        let options = FindOptions::builder()
+            .sort(doc! { "_id": order.direction() })
+            .limit(limit + 1)
+            .build();
+
+        let started_at = Instant::now();
+        let result = self.find_documents(filter, options).await;
+        self.metrics.observe_mongodb("find", started_at, &result);
+        let mut requests: Vec<Request> = Vec::new();
+        for doc in result? {
+            requests.push(Request::from_bson_document(&doc)?);
+        }
+
+        let next_cursor = if requests.len() as i64 > limit {
+            requests.truncate(limit as usize);
+            requests.last().map(|request| request.id.clone())
+        } else {
+            None
         };
-        let result = self.collection.insert_one(document, None).await?;
+        Ok(RequestPage {
+            requests,
+            next_cursor,
+        })
+    }
+
+    /// Aggregate usage statistics over the Requests in a time range
+    pub async fn stats(&self, query: &StatsQuery) -> Result<Vec<UsageStats>, ProxyError> {
+        let pipeline = query.to_pipeline(&self.hasher)?;
+        let options = AggregateOptions::builder().allow_disk_use(true).build();
+        let started_at = Instant::now();
+        let documents = self.aggregate_documents(pipeline, options).await;
+        self.metrics.observe_mongodb("aggregate", started_at, &documents);
+        let mut result: Vec<UsageStats> = Vec::new();
+        for doc in documents? {
+            result.push(UsageStats::from_bson_document(&doc)?);
+        }
         Ok(result)
     }
 
-    /// Get all existing Requests
-    pub async fn get_all(&self) -> Result<Vec<Request>, ProxyError> {
-        let mut cursor = self.collection.find(None, None).await?;
-        let mut result: Vec<Request> = Vec::new();
+    async fn find_documents(&self, filter: Document, options: FindOptions) -> Result<Vec<Document>, ProxyError> {
+        let mut cursor = self.collection.find(filter, options).await?;
+        let mut documents = Vec::new();
         while let Some(doc) = cursor.next().await {
-            result.push(Request::from_bson_document(&doc?)?);
+            documents.push(doc?);
         }
-        Ok(result)
+        Ok(documents)
     }
 
-    /// Find all existing Requests by API key
-    pub async fn get_by_key(&self, key: &str) -> Result<Vec<Request>, ProxyError> {
-        let filter = doc! {
-            "authorization": key.to_string(),
-        };
-        let mut cursor = self.collection.find(filter, None).await?;
-        let mut result: Vec<Request> = Vec::new();
+    async fn aggregate_documents(&self, pipeline: Vec<Document>, options: AggregateOptions) -> Result<Vec<Document>, ProxyError> {
+        let mut cursor = self.collection.aggregate(pipeline, options).await?;
+        let mut documents = Vec::new();
         while let Some(doc) = cursor.next().await {
-            result.push(Request::from_bson_document(&doc?)?);
+            documents.push(doc?);
         }
-        Ok(result)
+        Ok(documents)
+    }
+
+    /// Replace the raw `authorization` key of Requests logged before keys were hashed
+    /// with its `key_hash`, returns how many were rewritten
+    pub async fn migrate_key_hashes(&self) -> Result<u64, ProxyError> {
+        let options = FindOptions::builder()
+            .projection(doc! { "authorization": 1 })
+            .build();
+        let mut cursor = self.collection
+            .find(doc! { "authorization": { "$exists": true } }, options)
+            .await?;
+        let mut migrated = 0;
+        while let Some(doc) = cursor.next().await {
+            let doc = doc?;
+            let key_hash = doc.get_str("authorization").ok().map(|key| self.hasher.hash(key));
+            self.collection
+                .update_one(
+                    doc! { "_id": doc.get_object_id("_id")?.clone() },
+                    doc! {
+                        "$set": { "key_hash": optional(key_hash) },
+                        "$unset": { "authorization": "" },
+                    },
+                    None,
+                )
+                .await?;
+            migrated += 1;
+        }
+        Ok(migrated)
     }
 }
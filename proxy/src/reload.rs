@@ -0,0 +1,241 @@
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::Duration,
+};
+use actix_web::rt::signal::unix::{
+    signal,
+    SignalKind,
+};
+use chrono::Utc;
+use serde_json::{
+    json,
+    Value,
+};
+use super::config::{
+    Args,
+    Config,
+};
+use super::cors::CorsPolicy;
+use super::forwarding::{
+    RetryPolicy,
+    RouteTimeouts,
+};
+use super::policy::EndpointPolicy;
+use super::processor::RequestProcessor;
+use super::sinks;
+use super::streams::StreamPolicy;
+use super::upstream::UpstreamPool;
+use super::writer::RequestLogWriter;
+
+/// Settings applied to the running proxy on reload, changes to any other need a restart
+const RELOADABLE: &[&str] = &[
+    "endpoint_policy",
+    "cors_allowed_origins",
+    "cors_max_age_secs",
+    "upstreams",
+    "upstream_balancing",
+    "upstream_breaker_threshold",
+    "upstream_breaker_open_secs",
+    "upstream_retry_attempts",
+    "upstream_retry_base_backoff_ms",
+    "upstream_retry_max_backoff_ms",
+    "upstream_route_timeouts",
+    "request_log_batch_size",
+    "request_log_flush_interval_ms",
+    "request_log_overflow",
+    "request_log_spill_path",
+    "request_log_sinks",
+    "request_log_file_path",
+    "request_log_file_max_bytes",
+    "request_log_file_max_files",
+    "request_log_memory_capacity",
+    "stream_idle_timeout_secs",
+    "stream_max_per_key",
+    "stream_event_source",
+];
+
+/// Settings that rebuild the request log sinks when they change
+const SINK_SETTINGS: &[&str] = &[
+    "request_log_sinks",
+    "request_log_file_path",
+    "request_log_file_max_bytes",
+    "request_log_file_max_files",
+    "request_log_memory_capacity",
+];
+
+/// A setting replaced as a whole on reload, callers keep the snapshot they loaded until they're done
+pub struct Live<T>(RwLock<Arc<T>>);
+
+impl<T> Live<T> {
+    pub fn new(value: T) -> Self {
+        Live(RwLock::new(Arc::new(value)))
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// The parts of the running proxy a reload reconfigures
+pub struct Reloadable {
+    pub endpoint_policy: Arc<Live<EndpointPolicy>>,
+    pub cors_policy: Arc<Live<CorsPolicy>>,
+    pub upstream_retry: Arc<Live<RetryPolicy>>,
+    pub upstream_route_timeouts: Arc<Live<Vec<RouteTimeouts>>>,
+    pub stream_policy: Arc<Live<StreamPolicy>>,
+    pub upstreams: Arc<UpstreamPool>,
+    /// The scheme for upstreams given as `host:port`, `https` when `upstream_ca_bundle` is set,
+    /// which only changes on restart
+    pub upstream_scheme: &'static str,
+    pub log_writer: RequestLogWriter,
+    pub processor: RequestProcessor,
+}
+
+#[derive(Default)]
+struct ReloadStatus {
+    reloads: u64,
+    failures: u64,
+    last: Option<Value>,
+}
+
+/// Reloads the configuration on SIGHUP or request, keeping the running one when the new one is invalid
+pub struct Reloader {
+    args: Args,
+    started: Config,
+    current: Mutex<Config>,
+    targets: Reloadable,
+    status: Mutex<ReloadStatus>,
+}
+
+/// Names of the settings that differ between two configurations
+fn changed_settings(old: &Config, new: &Config) -> Vec<String> {
+    let (old, new) = match (serde_json::to_value(old), serde_json::to_value(new)) {
+        (Ok(Value::Object(old)), Ok(Value::Object(new))) => (old, new),
+        _ => return Vec::new(),
+    };
+    new.into_iter()
+        .filter(|(key, value)| old.get(key) != Some(value))
+        .map(|(key, _)| key)
+        .collect()
+}
+
+impl Reloader {
+    /// `args` are the command line the proxy started with, their overrides still win on reload
+    pub fn new(args: Args, config: Config, targets: Reloadable) -> Self {
+        Reloader {
+            args,
+            started: config.clone(),
+            current: Mutex::new(config),
+            targets,
+            status: Mutex::new(ReloadStatus::default()),
+        }
+    }
+
+    /// Load, validate and apply the configuration again, `trigger` says what asked for it
+    pub fn reload(&self, trigger: &str) -> Result<Value, String> {
+        let result = self.apply();
+        let mut status = self.status.lock().unwrap();
+        let at = Utc::now().to_rfc3339();
+        match &result {
+            Ok(outcome) => {
+                status.reloads += 1;
+                println!("Configuration reloaded on {}: {}", trigger, outcome);
+                status.last = Some(json!({
+                    "at": at,
+                    "trigger": trigger,
+                    "success": true,
+                    "outcome": outcome,
+                }));
+            }
+            Err(e) => {
+                status.failures += 1;
+                println!("Configuration reload on {} rejected, keeping the running configuration: {}", trigger, e);
+                status.last = Some(json!({
+                    "at": at,
+                    "trigger": trigger,
+                    "success": false,
+                    "error": e,
+                }));
+            }
+        }
+        result
+    }
+
+    fn apply(&self) -> Result<Value, String> {
+        let config = Config::load(&self.args)?;
+        config.validate()?;
+        // Everything that can fail happens before anything is swapped
+        let endpoint_policy = config.load_endpoint_policy()?;
+        let policy_warnings = config.policy_warnings(&endpoint_policy);
+        let upstream_urls = config.upstream_urls(self.targets.upstream_scheme)?;
+
+        let mut current = self.current.lock().unwrap();
+        let changed = changed_settings(&current, &config);
+        let restart_required: Vec<String> = changed_settings(&self.started, &config)
+            .into_iter()
+            .filter(|key| !RELOADABLE.contains(&key.as_str()))
+            .collect();
+        let sinks = if changed.iter().any(|key| SINK_SETTINGS.contains(&key.as_str())) {
+            Some(sinks::from_config(&config, &self.targets.processor))
+        } else {
+            None
+        };
+
+        self.targets.endpoint_policy.store(endpoint_policy);
+        self.targets.cors_policy.store(config.cors_policy());
+        self.targets.upstream_retry.store(config.retry_policy());
+        self.targets.upstream_route_timeouts.store(config.route_timeouts());
+        self.targets.stream_policy.store(config.stream_policy());
+        self.targets.upstreams.reconfigure(
+            upstream_urls,
+            config.balancing(),
+            config.upstream_breaker_threshold,
+            Duration::from_secs(config.upstream_breaker_open_secs),
+        );
+        self.targets.log_writer.reconfigure(
+            sinks,
+            config.request_log_batch_size,
+            Duration::from_millis(config.request_log_flush_interval_ms),
+            config.overflow_policy(),
+        );
+        *current = config;
+
+        Ok(json!({
+            "changed": changed,
+            "restart_required": restart_required,
+            "policy_warnings": policy_warnings,
+        }))
+    }
+
+    /// Reload whenever the process receives SIGHUP, must run on the actix system
+    pub async fn reload_on_hangup(self: Arc<Self>) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                println!("Error listening for SIGHUP, reload through POST /config-reload instead: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            let _ = self.reload("SIGHUP");
+        }
+    }
+
+    pub fn status(&self) -> Value {
+        let status = self.status.lock().unwrap();
+        json!({
+            "config_file": self.args.config_file(),
+            "reloads": status.reloads,
+            "failures": status.failures,
+            "last": status.last,
+        })
+    }
+}
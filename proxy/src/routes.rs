@@ -1,68 +1,357 @@
+use std::time::Duration;
 use actix_web::{
+    body::{
+        Body,
+        BodyStream,
+        SizedStream,
+    },
+    delete,
     get, 
+    post,
+    http::{
+        header,
+        Method,
+        StatusCode,
+    },
+    rt,
     web, 
     Error, 
     HttpRequest, 
     HttpResponse,
-    client::Client,
 };
-use url::Url;
+use futures::{
+    Stream,
+    StreamExt,
+    TryStreamExt,
+};
+use mongodb::{
+    bson::doc,
+    Database,
+};
 use serde_json::json;
+use super::access::{
+    Admin,
+    Caller,
+};
+use super::cache::AuthCache;
+use super::content::{
+    self,
+    CacheFill,
+    CachingStream,
+    ContentCache,
+};
+use super::credentials::{
+    request_key,
+    strip_api_key_param,
+};
 use super::error::JsonError;
+use super::forwarding::{
+    content_length,
+    is_idempotent_read,
+    is_unavailable,
+    RetryPolicy,
+    UploadLimit,
+    UpstreamClients,
+};
+use super::health::{
+    self,
+    Check,
+};
+use super::headers::{
+    prepare_forwarded,
+    strip_hop_by_hop,
+    TrustedProxies,
+};
+use super::metrics::Metrics;
+use super::middlewares::KeyValidator;
+use super::outage::AuthHealth;
 use super::processor::*;
+use super::reload::{
+    Live,
+    Reloader,
+};
+use super::stats::StatsQuery;
+use super::streams::{
+    is_long_lived,
+    wants_event_stream,
+    EventStream,
+    IdleTimeout,
+    StreamLimiter,
+};
+use super::trace::RequestContext;
+use super::upstream::{
+    cid_from_request,
+    UpstreamPool,
+};
+
+/// Pass a body through as a stream, keeping its length when it is known
+fn streaming_body<S, E>(length: Option<u64>, stream: S) -> Body
+where
+    S: Stream<Item = Result<web::Bytes, E>> + Unpin + 'static,
+    E: Into<Error> + 'static,
+{
+    match length {
+        Some(length) => Body::from_message(SizedStream::new(length, stream.map_err(Into::into))),
+        None => Body::from_message(BodyStream::new(stream)),
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
 pub async fn forward(
     req: HttpRequest,
-    body: web::Bytes,
-    forward_url: web::Data<Url>,
+    payload: web::Payload,
+    upstreams: web::Data<UpstreamPool>,
     app_data: web::Data<crate::State>,
-    client: web::Data<Client>,
+    clients: web::Data<UpstreamClients>,
+    retry: web::Data<Live<RetryPolicy>>,
+    content_cache: web::Data<ContentCache>,
+    trusted_proxies: web::Data<TrustedProxies>,
+    stream_limiter: web::Data<StreamLimiter>,
+    metrics: web::Data<Metrics>,
 ) -> Result<HttpResponse, Error> {
-    let request = NewRequest::from_http_request(&req).unwrap();
-    let result = app_data.container.processor.create(request).await;
+    let request = NewRequest::from_http_request(&req, app_data.container.processor.hasher());
+    let mut log = PendingRequest::new(request, app_data.container.log_writer.clone());
 
-    match result {
-        Ok(_) => println!("Request logged"),
-        Err(e) => println!("Error logging request: {}", e),
+    let query = req.uri().query().map(strip_api_key_param);
+    let cid = cid_from_request(req.uri().path(), req.uri().query());
+    let (client, read_timeout) = clients.route(req.uri().path());
+
+    let length = content_length(req.headers());
+    let has_body = length.is_some() || req.headers().contains_key(header::TRANSFER_ENCODING);
+    // A cached body is always the whole content, so ranges go to the upstream
+    let cache_key = if has_body || req.headers().contains_key(header::RANGE) {
+        None
+    } else {
+        content::cache_key(req.method(), req.uri().path(), query.as_deref())
+    };
+    if let Some((key, _)) = &cache_key {
+        let cached = content_cache.get(key).await;
+        let result = if cached.is_some() { "hit" } else { "miss" };
+        metrics.content_cache_lookups.with_label_values(&[result]).inc();
+        if let Some(cached) = cached {
+            log.set_response(200);
+            log.add_response_bytes(cached.body.len());
+            let mut client_resp = HttpResponse::Ok();
+            for (name, value) in &cached.headers {
+                client_resp.header(name.as_str(), value.as_str());
+            }
+            return Ok(client_resp.header("x-cache", "HIT").body(cached.body));
+        }
+    }
+
+    // Long-lived streams hold one of the key's slots until they end, and are only cut off once idle
+    let (stream_policy, stream_permit) = if is_long_lived(req.uri().path(), query.as_deref()) {
+        let key = request_key(&req).unwrap_or_default();
+        match stream_limiter.acquire(&key) {
+            Ok(permit) => (Some(stream_limiter.policy()), Some(permit)),
+            Err(e) => {
+                log.set_response(e.status);
+                return Err(e.into());
+            }
+        }
+    } else {
+        (None, None)
+    };
+    let event_stream = stream_policy.as_ref().is_some_and(|policy| policy.event_stream) && wants_event_stream(req.headers());
+    let read_timeout = match &stream_policy {
+        Some(policy) => Some(policy.idle_timeout),
+        None => read_timeout,
     };
 
-    println!("Processing ...");
-    let mut new_url = forward_url.as_ref().clone();
-    new_url.set_path(req.uri().path());
-    new_url.set_query(req.uri().query());
-
-    println!("Forwarded request URL: {:?}", new_url);
-    
-    let forwarded_req = client
-        .request_from(new_url.as_str(), req.head())
-        .no_decompress();
-    let forwarded_req = if let Some(addr) = req.head().peer_addr {
-        forwarded_req.header("x-forwarded-for", format!("{}", addr.ip()))
+    // Bodies without a Content-Length are only measured as they stream, the first chunk over the limit ends them
+    let upload_limit = req.extensions().get::<UploadLimit>().map(|limit| limit.0);
+    let request_bytes = log.request_bytes();
+    let received = request_bytes.clone();
+    let payload = payload.map(move |chunk| {
+        let chunk = chunk?;
+        received.set(received.get() + chunk.len() as u64);
+        match upload_limit {
+            Some(limit) if received.get() > limit => Err(Error::from(JsonError::payload_too_large(limit))),
+            _ => Ok(chunk),
+        }
+    });
+    let mut body = if has_body {
+        Some(streaming_body(length, payload))
     } else {
-        forwarded_req
+        None
     };
+    let retry = retry.load();
+    // A streamed body is gone after the first attempt, so only bodiless reads are retried
+    let max_attempts = if !has_body && is_idempotent_read(req.uri().path()) {
+        retry.max_attempts.max(1)
+    } else {
+        1
+    };
+    let context = req.extensions().get::<RequestContext>().cloned();
+
+    let mut attempt = 1;
+    let (upstream, span, res) = loop {
+        let upstream = match upstreams.select(cid.as_deref()) {
+            Some(upstream) => upstream,
+            None => {
+                log.set_response(503);
+                return Err(JsonError {
+                    msg: "No healthy IPFS upstream available".to_string(),
+                    status: 503,
+                    success: false,
+                    retry_after: upstreams.retry_after().map(|wait| wait.as_secs().max(1)),
+                }
+                .into());
+            }
+        };
+        log.set_upstream(upstream.upstream.url.as_str());
+        let mut new_url = upstream.upstream.url.clone();
+        new_url.set_path(req.uri().path());
+        new_url.set_query(query.as_deref());
+
+        // The client's Expect was already answered by actix, awc can't handle a second 100 Continue
+        let mut forwarded_req = client
+            .request_from(new_url.as_str(), req.head())
+            .no_decompress();
+        if let Some(read_timeout) = read_timeout {
+            forwarded_req = forwarded_req.timeout(read_timeout);
+        }
+        // EventSource can only GET, the IPFS API only answers POST
+        if event_stream {
+            forwarded_req = forwarded_req.method(Method::POST);
+        }
+        forwarded_req.headers_mut().remove(header::EXPECT);
+        prepare_forwarded(&req, forwarded_req.headers_mut(), &trusted_proxies);
+        let mut span = context.as_ref().map(|context| {
+            let mut span = context.span.client_span("upstream.request");
+            span.set_attribute("upstream", upstream.upstream.url.as_str());
+            span.set_attribute("attempt", attempt);
+            for (name, value) in context.propagation_headers(&span).iter() {
+                forwarded_req.headers_mut().insert(
+                    header::HeaderName::from_static(name),
+                    header::HeaderValue::from_str(value).unwrap(),
+                );
+            }
+            span
+        });
+
+        let result = forwarded_req.send_body(body.take().unwrap_or(Body::None)).await;
+        // The upstream isn't at fault when the client's body was cut off at its limit
+        if let (Err(_), Some(limit)) = (&result, upload_limit) {
+            if request_bytes.get() > limit {
+                log.set_response(413);
+                return Err(JsonError::payload_too_large(limit).into());
+            }
+        }
+        let failure = match &result {
+            Ok(res) => {
+                if let Some(span) = span.as_mut() {
+                    span.set_attribute("http.status_code", res.status().as_u16());
+                }
+                if is_unavailable(res.status()) {
+                    Some("status")
+                } else {
+                    None
+                }
+            }
+            Err(_) => Some("send"),
+        };
+        match failure {
+            Some(kind) => {
+                upstream.record_failure();
+                if let Some(span) = span.as_mut() {
+                    span.set_error();
+                }
+                metrics.upstream_errors
+                    .with_label_values(&[upstream.upstream.url.as_str(), kind])
+                    .inc();
+            }
+            None => upstream.record_success(),
+        }
 
-    let mut res = forwarded_req.send_body(body).await.map_err(Error::from)?;
+        let retryable = match &result {
+            Ok(res) => is_unavailable(res.status()),
+            Err(_) => true,
+        };
+        if retryable && attempt < max_attempts {
+            let backoff = retry.backoff(attempt);
+            println!("Retrying request to {} in {:?}", upstream.upstream.url, backoff);
+            drop(upstream);
+            rt::time::delay_for(backoff).await;
+            attempt += 1;
+            continue;
+        }
+
+        match result {
+            Ok(res) => break (upstream, span, res),
+            Err(e) => {
+                let e = Error::from(e);
+                log.set_response(e.as_response_error().status_code().as_u16());
+                return Err(e);
+            }
+        }
+    };
+    log.set_response(res.status().as_u16());
 
     let mut client_resp = HttpResponse::build(res.status());
-    
-    for (header_name, header_value) in res.headers().iter().filter(|(h, _)| *h != "connection") {
+    let mut res_headers = res.headers().clone();
+    strip_hop_by_hop(&mut res_headers);
+    if event_stream {
+        res_headers.remove(header::CONTENT_TYPE);
+        res_headers.remove(header::CONTENT_LENGTH);
+        client_resp
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache");
+    }
+    for (header_name, header_value) in res_headers.iter() {
         client_resp.header(header_name.clone(), header_value.clone());
     }
 
-    Ok(client_resp.body(res.body().await?))
+    // Only complete, unencoded 200s are stored, replaying them needs nothing but their headers.
+    // A body that may still fail in a trailer can't be known to be complete
+    let fill = match cache_key {
+        Some((key, cid)) => {
+            client_resp.header("x-cache", "MISS");
+            let storable = res.status() == StatusCode::OK
+                && !res.headers().contains_key(header::CONTENT_ENCODING)
+                && !content::may_fail_in_trailer(res.headers());
+            storable.then(|| CacheFill {
+                cache: content_cache.clone().into_inner(),
+                key,
+                cid,
+                headers: res
+                    .headers()
+                    .iter()
+                    .filter(|(name, _)| content::is_cached_header(name.as_str()))
+                    .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                    .collect(),
+                expected_length: content::announced_length(res.headers()),
+            })
+        }
+        None => None,
+    };
+
+    // Keep the upstream's connection slot, its span and the log record open until the response body is done
+    let length = content_length(res.headers());
+    let res = CachingStream::new(res, fill).inspect_ok(move |chunk| {
+        let _ = (&upstream, &span, &stream_permit);
+        log.add_response_bytes(chunk.len());
+    });
+    match stream_policy {
+        Some(policy) if event_stream => Ok(client_resp.streaming(EventStream::new(IdleTimeout::new(res, policy.idle_timeout)))),
+        Some(policy) => Ok(client_resp.streaming(IdleTimeout::new(res, policy.idle_timeout))),
+        None => Ok(client_resp.body(streaming_body(length, res))),
+    }
 }
 
 #[get("/requests")]
 pub async fn get_all_requests(
+    caller: Caller,
+    query: web::Query<RequestQuery>,
     app_data: web::Data<crate::State>,
 ) -> Result<HttpResponse, JsonError> {
-    let result = app_data.container.processor.get_all().await;
+    let result = app_data.container.processor.find(caller.scope(), &query).await;
     match result {
-        Ok(requests) => Ok(HttpResponse::Ok().json(json!({
+        Ok(page) => Ok(HttpResponse::Ok().json(json!({
             "status": 200,
             "sucess": true,
-            "payload": requests,
+            "payload": page.requests,
+            "next_cursor": page.next_cursor,
         }))),
         Err(e) => Err(e.into()),
     }
@@ -70,16 +359,188 @@ pub async fn get_all_requests(
 
 #[get("/requests/{key}")]
 pub async fn get_requests_by_key(
+    caller: Caller,
     key: web::Path<String>,
+    query: web::Query<RequestQuery>,
     app_data: web::Data<crate::State>,
 ) -> Result<HttpResponse, JsonError> {
-    let result = app_data.container.processor.get_by_key(&key).await;
+    let key = caller.check_key(&key)?;
+    let result = app_data.container.processor.find(Some(&key), &query).await;
     match result {
-        Ok(requests) => Ok(HttpResponse::Ok().json(json!({
+        Ok(page) => Ok(HttpResponse::Ok().json(json!({
             "status": 200,
             "success": true,
-            "payload": requests,
+            "payload": page.requests,
+            "next_cursor": page.next_cursor,
         }))),
         Err(e) => Err(e.into()),
     }
 }
+
+#[get("/stats")]
+pub async fn get_stats(
+    caller: Caller,
+    query: web::Query<StatsQuery>,
+    app_data: web::Data<crate::State>,
+) -> Result<HttpResponse, JsonError> {
+    let mut query = query.into_inner();
+    if let Some(key) = caller.scope() {
+        query.key = Some(key.to_string());
+    }
+    let result = app_data.container.processor.stats(&query).await;
+    match result {
+        Ok(stats) => Ok(HttpResponse::Ok().json(json!({
+            "status": 200,
+            "success": true,
+            "payload": stats,
+        }))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Called by the authentication service when a key is updated or deleted
+#[delete("/auth-cache/{key}")]
+pub async fn invalidate_auth_cache(
+    _: Admin,
+    key: web::Path<String>,
+    auth_cache: web::Data<AuthCache>,
+) -> HttpResponse {
+    let invalidated = auth_cache.invalidate(&key);
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": {
+            "invalidated": invalidated,
+        },
+    }))
+}
+
+#[get("/auth-status")]
+pub async fn get_auth_status(_: Admin, auth_health: web::Data<AuthHealth>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": auth_health.status(),
+    }))
+}
+
+#[get("/log-status")]
+pub async fn get_log_status(_: Admin, app_data: web::Data<crate::State>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": app_data.container.log_writer.status(),
+    }))
+}
+
+#[get("/config-status")]
+pub async fn get_config_status(_: Admin, reloader: web::Data<Reloader>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": reloader.status(),
+    }))
+}
+
+/// Reload the configuration file and environment, an invalid configuration leaves the running one in place
+#[post("/config-reload")]
+pub async fn reload_config(_: Admin, reloader: web::Data<Reloader>) -> Result<HttpResponse, JsonError> {
+    let outcome = reloader.reload("request").map_err(|msg| JsonError {
+        msg,
+        status: 400,
+        success: false,
+        retry_after: None,
+    })?;
+    Ok(HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": outcome,
+    })))
+}
+
+#[get("/upstreams")]
+pub async fn get_upstreams(_: Admin, upstreams: web::Data<UpstreamPool>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": upstreams.status(),
+    }))
+}
+
+#[get("/streams")]
+pub async fn get_streams(_: Admin, stream_limiter: web::Data<StreamLimiter>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": stream_limiter.status(),
+    }))
+}
+
+#[get("/content-cache")]
+pub async fn get_content_cache(_: Admin, content_cache: web::Data<ContentCache>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": content_cache.status(),
+    }))
+}
+
+#[delete("/content-cache")]
+pub async fn purge_content_cache(_: Admin, content_cache: web::Data<ContentCache>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": {
+            "purged": content_cache.purge(None),
+        },
+    }))
+}
+
+#[delete("/content-cache/{cid}")]
+pub async fn purge_content_cache_cid(
+    _: Admin,
+    cid: web::Path<String>,
+    content_cache: web::Data<ContentCache>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": {
+            "purged": content_cache.purge(Some(&cid)),
+        },
+    }))
+}
+
+#[get("/healthz")]
+pub async fn get_healthz() -> HttpResponse {
+    health::liveness()
+}
+
+#[get("/readyz")]
+pub async fn get_readyz(
+    database: web::Data<Database>,
+    validator: web::Data<KeyValidator>,
+    upstreams: web::Data<UpstreamPool>,
+) -> HttpResponse {
+    let timeout = Duration::from_secs(2);
+    let (mongodb, auth, upstream) = futures::join!(
+        Check::run(timeout, database.run_command(doc! { "ping": 1 }, None)),
+        Check::run(timeout, validator.ping()),
+        Check::run(timeout, async {
+            if upstreams.has_healthy() {
+                Ok(())
+            } else {
+                Err("no healthy IPFS upstream")
+            }
+        }),
+    );
+    health::readiness(vec![("mongodb", mongodb), ("auth", auth), ("upstreams", upstream)])
+}
+
+#[get("/metrics")]
+pub async fn get_metrics(app_data: web::Data<crate::State>, metrics: web::Data<Metrics>) -> HttpResponse {
+    metrics.log_queue_depth.set(app_data.container.log_writer.queued() as i64);
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
@@ -0,0 +1,271 @@
+use std::{
+    collections::VecDeque,
+    fs::{
+        self,
+        File,
+        OpenOptions,
+    },
+    io::{
+        self,
+        Write,
+    },
+    path::PathBuf,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use actix_web::{
+    error::BlockingError,
+    web,
+};
+use async_trait::async_trait;
+use serde_json::{
+    json,
+    Value,
+};
+use super::config::Config;
+use super::error::ProxyError;
+use super::processor::{
+    NewRequest,
+    RequestProcessor,
+};
+
+/// Destination for request log records, written to in batches by `RequestLogWriter`
+#[async_trait(?Send)]
+pub trait RequestSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn write(&self, batch: &[NewRequest]) -> Result<(), ProxyError>;
+
+    fn status(&self) -> Value {
+        json!({})
+    }
+}
+
+/// The sinks named in `request_log_sinks`, in order
+pub fn from_config(config: &Config, processor: &RequestProcessor) -> Vec<Box<dyn RequestSink>> {
+    config
+        .request_log_sinks
+        .iter()
+        .map(|name| -> Box<dyn RequestSink> {
+            match name.as_str() {
+                "mongodb" => Box::new(MongoSink::new(processor.clone())),
+                "file" => Box::new(FileSink::new(
+                    config.request_log_file_path.clone().into(),
+                    config.request_log_file_max_bytes,
+                    config.request_log_file_max_files,
+                )),
+                "stdout" => Box::new(StdoutSink),
+                "memory" => Box::new(MemorySink::new(config.request_log_memory_capacity)),
+                other => panic!("request_log_sinks contains unknown sink {}", other),
+            }
+        })
+        .collect()
+}
+
+/// Stores records in the `requests` collection
+pub struct MongoSink {
+    processor: RequestProcessor,
+}
+
+impl MongoSink {
+    pub fn new(processor: RequestProcessor) -> Self {
+        MongoSink {
+            processor,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl RequestSink for MongoSink {
+    fn name(&self) -> &'static str {
+        "mongodb"
+    }
+
+    async fn write(&self, batch: &[NewRequest]) -> Result<(), ProxyError> {
+        self.processor.create_many(batch.to_vec()).await?;
+        Ok(())
+    }
+}
+
+struct OpenFile {
+    file: File,
+    size: u64,
+}
+
+/// Appends JSON lines to a file, rotating it to `<path>.1` .. `<path>.<max_files>` when full.
+/// Writes run on the blocking thread pool
+pub struct FileSink {
+    log: Arc<RotatingFile>,
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    current: Mutex<Option<OpenFile>>,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf, max_bytes: u64, max_files: usize) -> Self {
+        FileSink {
+            log: Arc::new(RotatingFile {
+                path,
+                max_bytes,
+                max_files,
+                current: Mutex::new(None),
+            }),
+        }
+    }
+}
+
+impl RotatingFile {
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> io::Result<OpenFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok(OpenFile {
+            file,
+            size,
+        })
+    }
+
+    fn append(&self, lines: &str) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        if current.is_none() {
+            *current = Some(self.open()?);
+        }
+        if current.as_ref().unwrap().size > 0
+            && current.as_ref().unwrap().size + lines.len() as u64 > self.max_bytes
+        {
+            *current = None;
+            self.rotate()?;
+            *current = Some(self.open()?);
+        }
+        let open = current.as_mut().unwrap();
+        open.file.write_all(lines.as_bytes())?;
+        open.size += lines.len() as u64;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl RequestSink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn write(&self, batch: &[NewRequest]) -> Result<(), ProxyError> {
+        let lines = to_json_lines(batch)?;
+        let log = self.log.clone();
+        web::block(move || log.append(&lines)).await.map_err(blocking_io)?;
+        Ok(())
+    }
+
+    fn status(&self) -> Value {
+        let size = self.log.current.lock().unwrap().as_ref().map(|open| open.size);
+        json!({
+            "path": self.log.path.display().to_string(),
+            "size": size,
+        })
+    }
+}
+
+/// Prints records as JSON lines, for running under a log collector
+pub struct StdoutSink;
+
+#[async_trait(?Send)]
+impl RequestSink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    async fn write(&self, batch: &[NewRequest]) -> Result<(), ProxyError> {
+        let lines = to_json_lines(batch)?;
+        let stdout = std::io::stdout();
+        stdout.lock().write_all(lines.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Keeps the most recent records in memory, they are listed in its status
+pub struct MemorySink {
+    capacity: usize,
+    entries: Mutex<VecDeque<NewRequest>>,
+}
+
+impl MemorySink {
+    pub fn new(capacity: usize) -> Self {
+        MemorySink {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl RequestSink for MemorySink {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn write(&self, batch: &[NewRequest]) -> Result<(), ProxyError> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut entries = self.entries.lock().unwrap();
+        for request in batch {
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(request.clone());
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> Value {
+        let entries = self.entries.lock().unwrap();
+        json!({
+            "capacity": self.capacity,
+            "recent": *entries,
+        })
+    }
+}
+
+/// The I/O error from a `web::block` call, or one saying the blocking pool went away
+pub fn blocking_io(e: BlockingError<io::Error>) -> io::Error {
+    match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => io::Error::new(io::ErrorKind::Other, "blocking I/O was canceled"),
+    }
+}
+
+pub fn to_json_lines(batch: &[NewRequest]) -> Result<String, ProxyError> {
+    let mut lines = String::new();
+    for request in batch {
+        lines.push_str(&serde_json::to_string(request)?);
+        lines.push('\n');
+    }
+    Ok(lines)
+}
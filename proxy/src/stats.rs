@@ -0,0 +1,226 @@
+use bson::{
+    Bson,
+    Document,
+};
+use chrono::{
+    prelude::*,
+    Duration,
+    Utc,
+};
+use mongodb::bson::doc;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use super::error::ProxyError;
+use super::keys::KeyHasher;
+
+/// Upper bounds of the latency histogram buckets in milliseconds, percentiles are reported as the
+/// bound of the bucket they fall in, capped at the slowest request
+const LATENCY_BOUNDS_MS: &[i64] = &[
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 30_000, 60_000, 300_000, 600_000,
+];
+
+/// Histogram bucket for requests slower than the last bound
+const OVERFLOW_BUCKET: i64 = -1;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Hour,
+    Day,
+}
+
+impl Interval {
+    fn date_format(&self) -> &'static str {
+        match self {
+            Interval::Hour => "%Y-%m-%dT%H:00:00Z",
+            Interval::Day => "%Y-%m-%d",
+        }
+    }
+}
+
+/// Range and grouping for usage statistics, taken from the query string
+#[derive(Deserialize, Debug)]
+pub struct StatsQuery {
+    /// Defaults to 24 hours before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// Defaults to now
+    pub to: Option<DateTime<Utc>>,
+    pub interval: Option<Interval>,
+    /// Comma separated list of `key`, `endpoint` and `bucket`, defaults to `bucket`
+    pub group_by: Option<String>,
+    pub key: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Latency {
+    pub p50: Option<i64>,
+    pub p90: Option<i64>,
+    pub p99: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UsageStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    pub requests: i64,
+    pub errors: i64,
+    pub error_rate: f64,
+    pub latency_ms: Latency,
+    pub request_bytes: i64,
+    pub response_bytes: i64,
+}
+
+impl StatsQuery {
+    /// Build the aggregation pipeline over the `requests` collection
+    pub fn to_pipeline(&self, hasher: &KeyHasher) -> Result<Vec<Document>, ProxyError> {
+        let to = self.to.unwrap_or_else(Utc::now);
+        let from = self.from.unwrap_or(to - Duration::hours(24));
+        if from >= to {
+            return Err(ProxyError::InvalidQuery("from must be before to".to_string()));
+        }
+        let interval = self.interval.unwrap_or(Interval::Hour);
+
+        let mut filter = doc! {
+            "created_at": { "$gte": from, "$lt": to },
+        };
+        if let Some(key) = &self.key {
+            filter.insert("key_hash", hasher.hash(key));
+        }
+
+        // `/api/v0/...` calls are grouped by full path, gateway paths by their first segment
+        let endpoint = doc! {
+            "$cond": [
+                { "$eq": [{ "$substrCP": ["$path", 0, 5] }, "/api/"] },
+                "$path",
+                { "$concat": ["/", { "$arrayElemAt": [{ "$split": ["$path", "/"] }, 1] }] },
+            ]
+        };
+        let bucket = doc! {
+            "$dateToString": { "format": interval.date_format(), "date": "$created_at" }
+        };
+
+        let group_by = self.group_by.as_deref().unwrap_or("bucket");
+        let mut id = Document::new();
+        for dimension in group_by.split(',') {
+            match dimension.trim() {
+                "key" => id.insert("key", "$key_hash"),
+                "endpoint" => id.insert("endpoint", endpoint.clone()),
+                "bucket" => id.insert("bucket", bucket.clone()),
+                other => {
+                    return Err(ProxyError::InvalidQuery(format!("unknown group_by dimension: {}", other)));
+                }
+            };
+        }
+
+        // Requests without a duration sort before every number and land in no bucket
+        let mut branches = vec![Bson::Document(doc! {
+            "case": { "$lte": ["$duration_ms", Bson::Null] },
+            "then": Bson::Null,
+        })];
+        branches.extend(LATENCY_BOUNDS_MS.iter().map(|bound| {
+            Bson::Document(doc! {
+                "case": { "$lte": ["$duration_ms", bound] },
+                "then": bound,
+            })
+        }));
+        let latency_bucket = doc! {
+            "$switch": { "branches": branches, "default": OVERFLOW_BUCKET }
+        };
+
+        // Count per latency bucket first, so each group carries a few counts rather than every duration
+        Ok(vec![
+            doc! { "$match": filter },
+            doc! {
+                "$group": {
+                    "_id": { "group": id, "le": latency_bucket },
+                    "requests": { "$sum": 1 },
+                    "errors": { "$sum": { "$cond": [{ "$gte": ["$status", 400] }, 1, 0] } },
+                    "request_bytes": { "$sum": "$request_bytes" },
+                    "response_bytes": { "$sum": "$response_bytes" },
+                    "max_duration": { "$max": "$duration_ms" },
+                }
+            },
+            doc! {
+                "$group": {
+                    "_id": "$_id.group",
+                    "requests": { "$sum": "$requests" },
+                    "errors": { "$sum": "$errors" },
+                    "request_bytes": { "$sum": "$request_bytes" },
+                    "response_bytes": { "$sum": "$response_bytes" },
+                    "max_duration": { "$max": "$max_duration" },
+                    "latency": { "$push": { "le": "$_id.le", "count": "$requests" } },
+                }
+            },
+            doc! { "$sort": { "_id": 1 } },
+        ])
+    }
+}
+
+impl UsageStats {
+    pub fn from_bson_document(doc: &Document) -> Result<Self, ProxyError> {
+        let id = doc.get_document("_id")?;
+        let optional_str = |key: &str| id.get_str(key).ok().map(|value| value.to_string());
+
+        let max_duration = number(doc.get("max_duration").unwrap_or(&Bson::Null)).unwrap_or(0);
+        let mut histogram: Vec<(i64, i64)> = doc
+            .get_array("latency")?
+            .iter()
+            .filter_map(|bucket| {
+                let bucket = bucket.as_document()?;
+                let le = match number(bucket.get("le")?)? {
+                    OVERFLOW_BUCKET => max_duration,
+                    le => le.min(max_duration),
+                };
+                Some((le, number(bucket.get("count")?)?))
+            })
+            .collect();
+        histogram.sort_unstable();
+
+        let requests = number(doc.get("requests").unwrap_or(&Bson::Null)).unwrap_or(0);
+        let errors = number(doc.get("errors").unwrap_or(&Bson::Null)).unwrap_or(0);
+        Ok(UsageStats {
+            key: optional_str("key"),
+            endpoint: optional_str("endpoint"),
+            bucket: optional_str("bucket"),
+            requests,
+            errors,
+            error_rate: if requests > 0 { errors as f64 / requests as f64 } else { 0.0 },
+            latency_ms: Latency {
+                p50: percentile(&histogram, 50),
+                p90: percentile(&histogram, 90),
+                p99: percentile(&histogram, 99),
+            },
+            request_bytes: number(doc.get("request_bytes").unwrap_or(&Bson::Null)).unwrap_or(0),
+            response_bytes: number(doc.get("response_bytes").unwrap_or(&Bson::Null)).unwrap_or(0),
+        })
+    }
+}
+
+fn number(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(n) => Some(i64::from(*n)),
+        Bson::Int64(n) => Some(*n),
+        Bson::Double(n) => Some(*n as i64),
+        _ => None,
+    }
+}
+
+/// Nearest-rank percentile of a histogram of `(upper bound, count)` sorted by bound
+fn percentile(histogram: &[(i64, i64)], p: i64) -> Option<i64> {
+    let total: i64 = histogram.iter().map(|(_, count)| count).sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = ((p * total + 99) / 100).max(1);
+    let mut seen = 0;
+    histogram.iter().find_map(|(le, count)| {
+        seen += count;
+        (seen >= rank).then_some(*le)
+    })
+}
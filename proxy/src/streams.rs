@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    task::{
+        Context,
+        Poll,
+    },
+    time::Duration,
+};
+use actix_web::{
+    http::header::{
+        self,
+        HeaderMap,
+    },
+    rt::time::{
+        delay_for,
+        Delay,
+        Instant,
+    },
+    web::{
+        Bytes,
+        BytesMut,
+    },
+};
+use futures::{
+    Future,
+    Stream,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use super::error::JsonError;
+use super::policy::normalize_path;
+use super::reload::Live;
+
+/// IPFS API endpoints that stream for as long as the client listens
+pub const LONG_LIVED_PATHS: &[&str] = &[
+    "/api/v0/pubsub/sub",
+    "/api/v0/log/tail",
+];
+
+/// Endpoints that only stream when polling, e.g. `stats/bw?poll=true`
+pub const POLLING_PATHS: &[&str] = &[
+    "/api/v0/stats/bw",
+];
+
+/// Whether a request opens a stream that only ends when one side gives up
+pub fn is_long_lived(path: &str, query: Option<&str>) -> bool {
+    let path = normalize_path(path);
+    if LONG_LIVED_PATHS.contains(&path.as_str()) {
+        return true;
+    }
+    POLLING_PATHS.contains(&path.as_str())
+        && url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .any(|(name, value)| name == "poll" && value == "true")
+}
+
+/// Whether the client asked for Server-Sent Events, which browsers' `EventSource` does
+pub fn wants_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|accept| accept.split(',').any(|media| media.trim().starts_with("text/event-stream")))
+}
+
+/// How long-lived streams are passed through
+#[derive(Clone, Debug)]
+pub struct StreamPolicy {
+    /// A stream the upstream sends nothing on for this long is ended
+    pub idle_timeout: Duration,
+    pub max_per_key: usize,
+    /// Serve `Accept: text/event-stream` requests as Server-Sent Events
+    pub event_stream: bool,
+}
+
+/// Counts the long-lived streams each key has open
+pub struct StreamLimiter {
+    policy: Arc<Live<StreamPolicy>>,
+    active: Mutex<HashMap<String, usize>>,
+}
+
+/// Holds one of a key's stream slots until dropped
+pub struct StreamPermit {
+    limiter: Arc<StreamLimiter>,
+    key: String,
+}
+
+impl StreamLimiter {
+    pub fn new(policy: Arc<Live<StreamPolicy>>) -> Self {
+        StreamLimiter {
+            policy,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a stream slot for `key`, refusing with 429 when its streams are all in use
+    pub fn acquire(self: &Arc<Self>, key: &str) -> Result<StreamPermit, JsonError> {
+        let max_per_key = self.policy.load().max_per_key;
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(key.to_string()).or_insert(0);
+        if *count >= max_per_key {
+            return Err(JsonError {
+                msg: format!("APIKey already has {} streams open", max_per_key),
+                status: 429,
+                success: false,
+                retry_after: None,
+            });
+        }
+        *count += 1;
+        Ok(StreamPermit {
+            limiter: self.clone(),
+            key: key.to_string(),
+        })
+    }
+
+    pub fn policy(&self) -> Arc<StreamPolicy> {
+        self.policy.load()
+    }
+
+    pub fn status(&self) -> Value {
+        let active = self.active.lock().unwrap();
+        json!({
+            "open": active.values().sum::<usize>(),
+            "keys": active.len(),
+            "max_per_key": self.policy.load().max_per_key,
+        })
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        let mut active = self.limiter.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Ends a stream once nothing has come through it for `timeout`
+pub struct IdleTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    delay: Delay,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        IdleTimeout {
+            inner,
+            timeout,
+            delay: delay_for(timeout),
+        }
+    }
+}
+
+impl<S, E> Stream for IdleTimeout<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(item) => {
+                let deadline = Instant::now() + self.timeout;
+                self.delay.reset(deadline);
+                Poll::Ready(item)
+            }
+            Poll::Pending => match Pin::new(&mut self.delay).poll(cx) {
+                Poll::Ready(()) => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+/// Frames each line of an upstream's newline-delimited output as a Server-Sent Event
+pub struct EventStream<S> {
+    inner: S,
+    pending: BytesMut,
+}
+
+impl<S> EventStream<S> {
+    pub fn new(inner: S) -> Self {
+        EventStream {
+            inner,
+            pending: BytesMut::new(),
+        }
+    }
+
+    /// Every complete line received so far as `data:` events
+    fn events(&mut self) -> BytesMut {
+        let mut events = BytesMut::new();
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            let line = self.pending.split_to(end + 1);
+            let line = line.strip_suffix(b"\n").unwrap_or(&line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if !line.is_empty() {
+                events.extend_from_slice(b"data: ");
+                events.extend_from_slice(line);
+                events.extend_from_slice(b"\n\n");
+            }
+        }
+        events
+    }
+}
+
+impl<S, E> Stream for EventStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.pending.extend_from_slice(&chunk);
+                    let events = self.events();
+                    if !events.is_empty() {
+                        return Poll::Ready(Some(Ok(events.freeze())));
+                    }
+                }
+                Poll::Ready(None) if !self.pending.is_empty() => {
+                    // The last line may not end in a newline
+                    self.pending.extend_from_slice(b"\n");
+                    let events = self.events();
+                    return Poll::Ready(Some(Ok(events.freeze())));
+                }
+                polled => return polled,
+            }
+        }
+    }
+}
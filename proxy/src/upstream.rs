@@ -0,0 +1,284 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+use actix_web::rt;
+use rustls::ClientConfig;
+use serde_json::{
+    json,
+    Value,
+};
+use url::Url;
+use super::breaker::{
+    BreakerState,
+    CircuitBreaker,
+};
+use super::tls::client_builder;
+
+/// How the pool picks an upstream for a request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Balancing {
+    RoundRobin,
+    LeastConnections,
+    /// Rendezvous hashing on the CID, falls back to round robin for requests without one
+    ConsistentHash,
+}
+
+impl Balancing {
+    /// Parse `round_robin`, `least_connections` or `consistent_hash`
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "round_robin" => Some(Balancing::RoundRobin),
+            "least_connections" => Some(Balancing::LeastConnections),
+            "consistent_hash" => Some(Balancing::ConsistentHash),
+            _ => None,
+        }
+    }
+}
+
+/// A single IPFS API node
+pub struct Upstream {
+    pub url: Url,
+    active: AtomicUsize,
+    probe_healthy: AtomicBool,
+    breaker: CircuitBreaker,
+}
+
+impl Upstream {
+    fn new(url: Url, breaker: CircuitBreaker) -> Self {
+        Upstream {
+            url,
+            active: AtomicUsize::new(0),
+            probe_healthy: AtomicBool::new(true),
+            breaker,
+        }
+    }
+
+    /// Healthy when the last probe passed and its circuit breaker isn't open
+    pub fn is_healthy(&self) -> bool {
+        self.probe_healthy.load(Ordering::Relaxed) && self.breaker.state() != BreakerState::Open
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn status(&self) -> Value {
+        json!({
+            "url": self.url.as_str(),
+            "healthy": self.is_healthy(),
+            "active_connections": self.active_connections(),
+            "breaker": self.breaker.state(),
+            "consecutive_failures": self.breaker.consecutive_failures(),
+        })
+    }
+}
+
+/// Holds an upstream's connection slot for the duration of a forwarded request
+pub struct UpstreamGuard {
+    pub upstream: Arc<Upstream>,
+    settled: AtomicBool,
+}
+
+impl UpstreamGuard {
+    fn new(upstream: Arc<Upstream>) -> Self {
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+        UpstreamGuard {
+            upstream,
+            settled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn record_success(&self) {
+        self.settled.store(true, Ordering::Relaxed);
+        self.upstream.breaker.record_success();
+    }
+
+    /// Count a failed call, opening the upstream's breaker after too many in a row
+    pub fn record_failure(&self) {
+        self.settled.store(true, Ordering::Relaxed);
+        self.upstream.breaker.record_failure();
+        if self.upstream.breaker.state() == BreakerState::Open {
+            println!(
+                "Upstream {} circuit open after {} failures",
+                self.upstream.url,
+                self.upstream.breaker.consecutive_failures(),
+            );
+        }
+    }
+}
+
+impl Drop for UpstreamGuard {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::Relaxed);
+        if !self.settled.load(Ordering::Relaxed) {
+            self.upstream.breaker.release_probe();
+        }
+    }
+}
+
+struct Members {
+    upstreams: Vec<Arc<Upstream>>,
+    balancing: Balancing,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+/// Pool of IPFS API nodes the proxy forwards to
+pub struct UpstreamPool {
+    members: RwLock<Members>,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    /// Each upstream gets its own breaker, opened after `failure_threshold` failures in a row
+    pub fn new(urls: Vec<Url>, balancing: Balancing, failure_threshold: u32, open_duration: Duration) -> Self {
+        UpstreamPool {
+            members: RwLock::new(Members {
+                upstreams: urls
+                    .into_iter()
+                    .map(|url| Arc::new(Upstream::new(url, CircuitBreaker::new(failure_threshold, open_duration))))
+                    .collect(),
+                balancing,
+                failure_threshold,
+                open_duration,
+            }),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Replace the pool's members and settings. Upstreams that stay keep their connections and
+    /// breaker state unless the breaker settings change, requests already forwarded are unaffected
+    pub fn reconfigure(&self, urls: Vec<Url>, balancing: Balancing, failure_threshold: u32, open_duration: Duration) {
+        let mut members = self.members.write().unwrap();
+        let same_breaker = members.failure_threshold == failure_threshold && members.open_duration == open_duration;
+        let upstreams = urls
+            .into_iter()
+            .map(|url| {
+                members
+                    .upstreams
+                    .iter()
+                    .find(|u| same_breaker && u.url == url)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(Upstream::new(url, CircuitBreaker::new(failure_threshold, open_duration))))
+            })
+            .collect();
+        *members = Members {
+            upstreams,
+            balancing,
+            failure_threshold,
+            open_duration,
+        };
+    }
+
+    /// Pick a healthy upstream whose breaker lets the call through, `cid` is used for consistent hashing
+    pub fn select(&self, cid: Option<&str>) -> Option<UpstreamGuard> {
+        let members = self.members.read().unwrap();
+        let mut healthy: Vec<&Arc<Upstream>> = members.upstreams.iter().filter(|u| u.is_healthy()).collect();
+        // A half-open upstream already running its probe refuses the call, so try the next pick
+        while let Some(selected) = self.pick(members.balancing, &healthy, cid) {
+            if selected.breaker.allow() {
+                return Some(UpstreamGuard::new(selected.clone()));
+            }
+            healthy.retain(|u| !Arc::ptr_eq(*u, &selected));
+        }
+        None
+    }
+
+    pub fn has_healthy(&self) -> bool {
+        self.members.read().unwrap().upstreams.iter().any(|u| u.is_healthy())
+    }
+
+    /// Time until an upstream with an open breaker may be tried again, if any is waiting on one
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.members
+            .read()
+            .unwrap()
+            .upstreams
+            .iter()
+            .filter(|u| u.probe_healthy.load(Ordering::Relaxed) && u.breaker.state() != BreakerState::Closed)
+            .map(|u| u.breaker.retry_after())
+            .min()
+    }
+
+    fn pick(&self, balancing: Balancing, healthy: &[&Arc<Upstream>], cid: Option<&str>) -> Option<Arc<Upstream>> {
+        if healthy.is_empty() {
+            return None;
+        }
+
+        let selected = match (balancing, cid) {
+            (Balancing::LeastConnections, _) => healthy
+                .iter()
+                .min_by_key(|u| u.active_connections())
+                .copied(),
+            (Balancing::ConsistentHash, Some(cid)) => healthy
+                .iter()
+                .max_by_key(|u| {
+                    let mut hasher = DefaultHasher::new();
+                    cid.hash(&mut hasher);
+                    u.url.as_str().hash(&mut hasher);
+                    hasher.finish()
+                })
+                .copied(),
+            _ => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                healthy.get(next % healthy.len()).copied()
+            }
+        };
+
+        selected.cloned()
+    }
+
+    pub fn status(&self) -> Value {
+        Value::Array(self.members.read().unwrap().upstreams.iter().map(|u| u.status()).collect())
+    }
+
+    /// Probe every upstream's `/api/v0/id` forever, must run on the actix system
+    pub async fn run_health_checks(self: Arc<Self>, interval: Duration, timeout: Duration, tls: Option<Arc<ClientConfig>>) {
+        let client = client_builder(tls.as_ref()).timeout(timeout).finish();
+        let mut ticker = rt::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let upstreams = self.members.read().unwrap().upstreams.clone();
+            for upstream in &upstreams {
+                let mut probe_url = upstream.url.clone();
+                probe_url.set_path("/api/v0/id");
+                let healthy = match client.post(probe_url.as_str()).send().await {
+                    Ok(res) => res.status().is_success(),
+                    Err(_) => false,
+                };
+                let was_healthy = upstream.probe_healthy.swap(healthy, Ordering::Relaxed);
+                if was_healthy != healthy {
+                    println!("Upstream {} is now {}", upstream.url, if healthy { "healthy" } else { "unhealthy" });
+                }
+            }
+        }
+    }
+}
+
+/// Extract the CID a request is about, from `/ipfs/<cid>/...` or `?arg=<cid>/...`
+pub fn cid_from_request(path: &str, query: Option<&str>) -> Option<String> {
+    let target = if let Some(rest) = path.strip_prefix("/ipfs/") {
+        rest.to_string()
+    } else {
+        let query = query?;
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| name == "arg")
+            .map(|(_, value)| value.into_owned())?
+    };
+    let target = target.trim_start_matches("/ipfs/");
+    target.split('/').next().filter(|cid| !cid.is_empty()).map(|cid| cid.to_string())
+}
@@ -0,0 +1,355 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        atomic::{
+            AtomicU64,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use actix_web::{
+    rt,
+    web,
+};
+use futures::{
+    channel::{
+        mpsc,
+        oneshot,
+    },
+    SinkExt,
+    StreamExt,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use super::processor::NewRequest;
+use super::reload::Live;
+use super::sinks::{
+    blocking_io,
+    to_json_lines,
+    RequestSink,
+};
+
+/// Writes a sink gets at a batch before it goes to the overflow policy
+const MAX_ATTEMPTS: u32 = 3;
+
+/// What to do with a record when the queue is full
+#[derive(Clone, Debug)]
+pub enum OverflowPolicy {
+    /// Discard the record and count it
+    Drop,
+    /// Append the record as a JSON line to a local file
+    Spill(PathBuf),
+}
+
+impl OverflowPolicy {
+    /// Parse `drop` or `spill`, spilling goes to `spill_path`
+    pub fn parse(name: &str, spill_path: PathBuf) -> Option<Self> {
+        match name {
+            "drop" => Some(OverflowPolicy::Drop),
+            "spill" => Some(OverflowPolicy::Spill(spill_path)),
+            _ => None,
+        }
+    }
+}
+
+enum Message {
+    Entry(Box<NewRequest>),
+    Shutdown(oneshot::Sender<()>),
+}
+
+#[derive(Default)]
+struct Stats {
+    queued: AtomicUsize,
+    dropped: AtomicU64,
+    spilled: AtomicU64,
+}
+
+struct Sink {
+    sink: Box<dyn RequestSink>,
+    written: AtomicU64,
+    failed_batches: AtomicU64,
+    retry: Mutex<Retry>,
+}
+
+/// Records a sink failed to take, written again ahead of its next batch
+#[derive(Default)]
+struct Retry {
+    requests: Vec<NewRequest>,
+    attempts: u32,
+}
+
+fn wrap_sinks(sinks: Vec<Box<dyn RequestSink>>) -> Vec<Sink> {
+    sinks
+        .into_iter()
+        .map(|sink| Sink {
+            sink,
+            written: AtomicU64::new(0),
+            failed_batches: AtomicU64::new(0),
+            retry: Mutex::new(Retry::default()),
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+struct Batching {
+    size: usize,
+    flush_interval: Duration,
+}
+
+/// Handle to the background task that writes request logs in batches to every sink
+#[derive(Clone)]
+pub struct RequestLogWriter {
+    sender: mpsc::Sender<Message>,
+    overflow: Arc<Live<OverflowPolicy>>,
+    batching: Arc<Live<Batching>>,
+    stats: Arc<Stats>,
+    sinks: Arc<Live<Vec<Sink>>>,
+}
+
+impl RequestLogWriter {
+    /// Start the writer task, must be called on the actix system
+    pub fn start(
+        sinks: Vec<Box<dyn RequestSink>>,
+        capacity: usize,
+        batch_size: usize,
+        flush_interval: Duration,
+        overflow: OverflowPolicy,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let writer = RequestLogWriter {
+            sender,
+            overflow: Arc::new(Live::new(overflow)),
+            batching: Arc::new(Live::new(Batching {
+                size: batch_size,
+                flush_interval,
+            })),
+            stats: Arc::new(Stats::default()),
+            sinks: Arc::new(Live::new(wrap_sinks(sinks))),
+        };
+        rt::spawn(writer.clone().run(receiver));
+        writer
+    }
+
+    /// Apply new settings to the running writer, `sinks` replaces every sink when given.
+    /// A batch being written finishes on the sinks it started with, records the old sinks
+    /// were still retrying go to the overflow policy
+    pub fn reconfigure(
+        &self,
+        sinks: Option<Vec<Box<dyn RequestSink>>>,
+        batch_size: usize,
+        flush_interval: Duration,
+        overflow: OverflowPolicy,
+    ) {
+        self.overflow.store(overflow);
+        if let Some(sinks) = sinks {
+            let replaced = self.sinks.load();
+            self.sinks.store(wrap_sinks(sinks));
+            for sink in replaced.iter() {
+                let requests = std::mem::take(&mut sink.retry.lock().unwrap().requests);
+                if !requests.is_empty() {
+                    self.overflow(requests);
+                }
+            }
+        }
+        self.batching.store(Batching {
+            size: batch_size,
+            flush_interval,
+        });
+    }
+
+    /// Queue a record without waiting, applying the overflow policy if the queue is full
+    pub fn enqueue(&self, request: NewRequest) {
+        let mut sender = self.sender.clone();
+        match sender.try_send(Message::Entry(Box::new(request))) {
+            Ok(()) => {
+                self.stats.queued.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                if let Message::Entry(request) = e.into_inner() {
+                    self.overflow(vec![*request]);
+                }
+            }
+        }
+    }
+
+    /// Write everything still queued and stop the writer task
+    pub async fn shutdown(&self) {
+        let (done, flushed) = oneshot::channel();
+        let mut sender = self.sender.clone();
+        if sender.send(Message::Shutdown(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    /// Records waiting in the queue
+    pub fn queued(&self) -> usize {
+        self.stats.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> Value {
+        json!({
+            "queued": self.stats.queued.load(Ordering::Relaxed),
+            "dropped": self.stats.dropped.load(Ordering::Relaxed),
+            "spilled": self.stats.spilled.load(Ordering::Relaxed),
+            "sinks": self.sinks.load().iter().map(|sink| json!({
+                "name": sink.sink.name(),
+                "written": sink.written.load(Ordering::Relaxed),
+                "failed_batches": sink.failed_batches.load(Ordering::Relaxed),
+                "retrying": sink.retry.lock().unwrap().requests.len(),
+                "status": sink.sink.status(),
+            })).collect::<Vec<Value>>(),
+        })
+    }
+
+    /// Apply the overflow policy without waiting, spilling happens in the background
+    fn overflow(&self, requests: Vec<NewRequest>) {
+        match &*self.overflow.load() {
+            OverflowPolicy::Drop => {
+                self.stats.dropped.fetch_add(requests.len() as u64, Ordering::Relaxed);
+            }
+            OverflowPolicy::Spill(_) => {
+                let writer = self.clone();
+                rt::spawn(async move { writer.spill_or_drop(requests).await });
+            }
+        }
+    }
+
+    async fn spill_or_drop(&self, requests: Vec<NewRequest>) {
+        let count = requests.len() as u64;
+        if let OverflowPolicy::Spill(path) = &*self.overflow.load() {
+            let spilled = match to_json_lines(&requests) {
+                Ok(lines) => {
+                    let path = path.clone();
+                    web::block(move || spill(&path, &lines)).await.map_err(|e| blocking_io(e).to_string())
+                }
+                Err(e) => Err(e.to_string()),
+            };
+            match spilled {
+                Ok(()) => {
+                    self.stats.spilled.fetch_add(count, Ordering::Relaxed);
+                    return;
+                }
+                Err(e) => println!("Error spilling request logs to {}: {}", path.display(), e),
+            }
+        }
+        self.stats.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Write a batch to every sink. A sink that fails gets the records again with its next
+    /// batch, after `MAX_ATTEMPTS` failed writes in a row they go to the overflow policy
+    async fn flush(&self, batch: &mut Vec<NewRequest>) {
+        if batch.is_empty() {
+            return;
+        }
+        let requests: Vec<NewRequest> = std::mem::take(batch);
+        for sink in self.sinks.load().iter() {
+            let mut pending = std::mem::take(&mut sink.retry.lock().unwrap().requests);
+            pending.extend_from_slice(&requests);
+            match sink.sink.write(&pending).await {
+                Ok(()) => {
+                    sink.written.fetch_add(pending.len() as u64, Ordering::Relaxed);
+                    sink.retry.lock().unwrap().attempts = 0;
+                }
+                Err(e) => {
+                    println!("Error logging {} requests to {}: {}", pending.len(), sink.sink.name(), e);
+                    sink.failed_batches.fetch_add(1, Ordering::Relaxed);
+                    let gave_up = {
+                        let mut retry = sink.retry.lock().unwrap();
+                        retry.attempts += 1;
+                        if retry.attempts >= MAX_ATTEMPTS {
+                            retry.attempts = 0;
+                            true
+                        } else {
+                            retry.requests = std::mem::take(&mut pending);
+                            false
+                        }
+                    };
+                    if gave_up {
+                        self.spill_or_drop(pending).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hand records the sinks are still retrying to the overflow policy before stopping
+    async fn abandon_retries(&self) {
+        for sink in self.sinks.load().iter() {
+            let requests = std::mem::take(&mut sink.retry.lock().unwrap().requests);
+            if !requests.is_empty() {
+                println!("Giving up on {} requests for {}", requests.len(), sink.sink.name());
+                self.spill_or_drop(requests).await;
+            }
+        }
+    }
+
+    async fn run(self, mut receiver: mpsc::Receiver<Message>) {
+        let mut batch = Vec::new();
+        let mut deadline = Instant::now();
+        loop {
+            let batching = *self.batching.load();
+            let message = if batch.is_empty() {
+                receiver.next().await
+            } else {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match rt::time::timeout(remaining, receiver.next()).await {
+                    Ok(message) => message,
+                    Err(_) => {
+                        self.flush(&mut batch).await;
+                        continue;
+                    }
+                }
+            };
+
+            match message {
+                Some(Message::Entry(request)) => {
+                    self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    if batch.is_empty() {
+                        deadline = Instant::now() + batching.flush_interval;
+                    }
+                    batch.push(*request);
+                    if batch.len() >= batching.size {
+                        self.flush(&mut batch).await;
+                    }
+                }
+                Some(Message::Shutdown(done)) => {
+                    receiver.close();
+                    while let Some(message) = receiver.next().await {
+                        if let Message::Entry(request) = message {
+                            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                            batch.push(*request);
+                        }
+                    }
+                    self.flush(&mut batch).await;
+                    self.abandon_retries().await;
+                    let _ = done.send(());
+                    return;
+                }
+                None => {
+                    self.flush(&mut batch).await;
+                    self.abandon_retries().await;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn spill(path: &Path, lines: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(lines.as_bytes())
+}
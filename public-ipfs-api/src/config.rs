@@ -0,0 +1,116 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use ipfs_api::{
+    IpfsClient,
+    TryFromUri,
+};
+pub use service_common::config::{
+    exit_with_error,
+    optional,
+    Args,
+};
+use service_common::config::{
+    is_address,
+    redact_userinfo,
+};
+use super::trace::TraceExport;
+
+/// Environment variables are named `PUBLIC_API_<SETTING>`, e.g. `PUBLIC_API_LISTEN_ADDRESS`
+pub const ENV_PREFIX: &str = "PUBLIC_API";
+
+/// Public API settings, layered from defaults, a TOML file, `PUBLIC_API_*` environment variables
+/// and `--setting-name value` flags, each overriding the one before
+///
+/// Empty strings leave optional settings such as `tls_cert` unset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen_address: String,
+    pub tls_cert: String,
+    pub tls_key: String,
+    pub tls_reload_interval_secs: u64,
+    /// The IPFS node's API, ipfs-api's own default when empty
+    pub ipfs_api_url: String,
+    /// Largest body accepted for upload, callers behind the proxy also get their key's limit there
+    pub max_upload_bytes: u64,
+    pub trace_exporter: String,
+    pub trace_otlp_endpoint: String,
+    pub trace_batch_size: usize,
+    pub trace_flush_interval_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_address: "127.0.0.1:5001".to_string(),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            tls_reload_interval_secs: 30,
+            ipfs_api_url: String::new(),
+            max_upload_bytes: 262144,
+            trace_exporter: "none".to_string(),
+            trace_otlp_endpoint: "http://127.0.0.1:4318/v1/traces".to_string(),
+            trace_batch_size: 100,
+            trace_flush_interval_secs: 5,
+        }
+    }
+}
+
+impl Config {
+    /// Layer the TOML file from `--config` or `PUBLIC_API_CONFIG`, `PUBLIC_API_*` environment
+    /// variables and command line overrides over the defaults
+    pub fn load(args: &Args) -> Result<Self, String> {
+        args.load()
+    }
+
+    /// Check every setting, reporting all problems at once
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        if !is_address(&self.listen_address) {
+            errors.push(format!("listen_address must be host:port, got {:?}", self.listen_address));
+        }
+        if self.tls_cert.is_empty() != self.tls_key.is_empty() {
+            errors.push("tls_cert and tls_key must be set together".to_string());
+        }
+        if let Some(url) = optional(&self.ipfs_api_url) {
+            if IpfsClient::from_str(url).is_err() {
+                errors.push(format!("ipfs_api_url must be a URL, got {:?}", url));
+            }
+        }
+        if self.max_upload_bytes == 0 {
+            errors.push("max_upload_bytes must be at least 1".to_string());
+        }
+        if TraceExport::parse(&self.trace_exporter, &self.trace_otlp_endpoint).is_none() {
+            errors.push("trace_exporter must be none, stdout or otlp".to_string());
+        }
+        if !self.trace_otlp_endpoint.starts_with("http://") && !self.trace_otlp_endpoint.starts_with("https://") {
+            errors.push(format!("trace_otlp_endpoint must be a URL, got {:?}", self.trace_otlp_endpoint));
+        }
+        if self.trace_batch_size == 0 {
+            errors.push("trace_batch_size must be at least 1".to_string());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// The effective configuration as TOML, with credentials in URLs redacted
+    pub fn render(&self) -> String {
+        let mut shown = self.clone();
+        shown.ipfs_api_url = redact_userinfo(&shown.ipfs_api_url);
+        shown.trace_otlp_endpoint = redact_userinfo(&shown.trace_otlp_endpoint);
+        toml::to_string(&shown).expect("the configuration serializes to TOML")
+    }
+
+    /// A client for the configured IPFS node
+    pub fn ipfs_client(&self) -> IpfsClient {
+        match optional(&self.ipfs_api_url) {
+            Some(url) => IpfsClient::from_str(url).expect("ipfs_api_url is validated"),
+            None => IpfsClient::default(),
+        }
+    }
+}
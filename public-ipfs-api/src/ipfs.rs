@@ -0,0 +1,56 @@
+use std::{
+    io::Cursor,
+    sync::Arc,
+    time::Duration,
+};
+use ipfs_api::{
+    response::AddResponse,
+    IpfsClient,
+};
+use tokio::runtime::{
+    Builder,
+    Runtime,
+};
+
+/// The configured `IpfsClient` and the Tokio 1 runtime its calls run on. ipfs-api's hyper
+/// backend needs one and actix only provides Tokio 0.2, so every call is spawned there
+#[derive(Clone)]
+pub struct IpfsNode {
+    client: IpfsClient,
+    runtime: Arc<Runtime>,
+}
+
+impl IpfsNode {
+    pub fn start(client: IpfsClient) -> std::io::Result<Self> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("ipfs-client")
+            .enable_all()
+            .build()?;
+        Ok(IpfsNode {
+            client,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Ask the node for its version, the call is abandoned after `timeout`
+    pub async fn version(&self, timeout: Duration) -> Result<(), String> {
+        let client = self.client.clone();
+        self.runtime
+            .spawn(async move { tokio::time::timeout(timeout, client.version()).await })
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|_| format!("timed out after {:?}", timeout))?
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn add(&self, body: Vec<u8>) -> Result<AddResponse, String> {
+        let client = self.client.clone();
+        self.runtime
+            .spawn(async move { client.add(Cursor::new(body)).await })
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("{:?}", e))
+    }
+}
@@ -1,28 +1,85 @@
+use std::time::Duration;
 use actix_web::{
     self,
     web,
     HttpServer
 };
-use ipfs_api::IpfsClient;
+use config::{
+    Args,
+    Config,
+};
+use ipfs::IpfsNode;
+use tls::ReloadingCert;
+use metrics::Metrics;
+use service_common::{
+    health,
+    metrics::RequestMetrics,
+    tls,
+    trace,
+};
+use trace::{
+    RequestTracing,
+    TraceExport,
+    Tracer,
+};
 
+pub mod config;
+pub mod ipfs;
+pub mod metrics;
 pub mod routes;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()>{
 
-    let ip_address = "127.0.0.1:5001";
+    let args = Args::parse(config::ENV_PREFIX, &[], std::env::args().skip(1))
+        .unwrap_or_else(|e| config::exit_with_error(e));
+    let config = Config::load(&args)
+        .and_then(|config| config.validate().map(|_| config))
+        .unwrap_or_else(|e| config::exit_with_error(e));
+    if args.print_config {
+        print!("{}", config.render());
+        return Ok(());
+    }
+    let node = IpfsNode::start(config.ipfs_client())?;
+
+    print!("listening {}", config.listen_address);
 
-    print!("listening {}", ip_address);
+    let metrics = web::Data::new(Metrics::default());
+    let trace_export = TraceExport::parse(&config.trace_exporter, &config.trace_otlp_endpoint)
+        .expect("trace_exporter must be none, stdout or otlp");
+    let tracer = Tracer::start(
+        trace_export,
+        "public-ipfs-api",
+        config.trace_batch_size,
+        Duration::from_secs(config.trace_flush_interval_secs),
+    );
+    let upload_limit = web::Data::new(routes::UploadLimit(config.max_upload_bytes));
 
-    HttpServer::new(|| {
-        actix_web::App::new().data(IpfsClient::default())
+    let server = HttpServer::new(move || {
+        actix_web::App::new().data(node.clone())
+        .wrap(RequestMetrics::new(&metrics.http, "unmatched"))
+        .wrap(RequestTracing::new(tracer.clone(), "public_api.request"))
+        .app_data(metrics.clone())
+        .app_data(upload_limit.clone())
+        .service(routes::get_healthz)
+        .service(routes::get_readyz)
+        .service(routes::get_metrics)
         .service(
             web::scope("/")
             .service(routes::index)
             .service(routes::test_upload)
         )
-    })
-    .bind(ip_address)?
-    .run()
-    .await
+    });
+    let server = match (config::optional(&config.tls_cert), config::optional(&config.tls_key)) {
+        (Some(cert), Some(key)) => {
+            let cert = ReloadingCert::load(cert.as_ref(), key.as_ref())?;
+            cert.clone().watch(Duration::from_secs(config.tls_reload_interval_secs));
+            server.bind_rustls(&config.listen_address, tls::server_config(cert))?
+        }
+        (None, None) => server.bind(&config.listen_address)?,
+        _ => unreachable!("tls_cert and tls_key are validated together"),
+    };
+    server
+        .run()
+        .await
 }
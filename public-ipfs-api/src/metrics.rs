@@ -0,0 +1,32 @@
+use prometheus::{
+    exponential_buckets,
+    Histogram,
+    HistogramOpts,
+};
+use service_common::metrics::HttpMetrics;
+
+/// Prometheus collectors for the public API, rendered on `/metrics`
+pub struct Metrics {
+    pub http: HttpMetrics,
+    pub ipfs_add_bytes: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let http = HttpMetrics::default();
+        Metrics {
+            ipfs_add_bytes: http.register(Histogram::with_opts(
+                HistogramOpts::new("ipfs_add_bytes", "Size of files added to IPFS")
+                    .buckets(exponential_buckets(1024.0, 2.0, 9).unwrap()),
+            ).unwrap()),
+            http,
+        }
+    }
+}
+
+impl Metrics {
+    /// Everything registered, in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.http.render()
+    }
+}
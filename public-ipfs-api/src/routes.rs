@@ -1,7 +1,4 @@
-use std::{
-    boxed::Box,
-    io::Cursor,
-};
+use std::time::Duration;
 
 use actix_web::{
     error, 
@@ -9,14 +6,23 @@ use actix_web::{
     post, 
     web, 
     Error, 
+    HttpRequest,
     HttpResponse
 };
 
 use futures::StreamExt;
-use ipfs_api::IpfsClient;
 use serde::Serialize;
+use serde_json::json;
+use super::health::{
+    self,
+    Check,
+};
+use super::ipfs::IpfsNode;
+use super::metrics::Metrics;
+use super::trace::RequestContext;
 
-const MAX_SIZE: usize = 262144;
+/// The most bytes `test_upload` reads from a request body
+pub struct UploadLimit(pub u64);
 
 #[derive(Serialize)]
 struct IpfsResponse {
@@ -26,31 +32,74 @@ struct IpfsResponse {
 }
 
 #[get("")]
-async fn index(_client: web::Data<IpfsClient>) -> HttpResponse {
+async fn index(_node: web::Data<IpfsNode>) -> HttpResponse {
     HttpResponse::Ok().body("it works as post")
 }
 
 #[post("")]
-async fn test_upload(mut payload: web::Payload, client: web::Data<IpfsClient>) -> Result<HttpResponse, Error> {
+async fn test_upload(
+    req: HttpRequest,
+    mut payload: web::Payload,
+    node: web::Data<IpfsNode>,
+    metrics: web::Data<Metrics>,
+    limit: web::Data<UploadLimit>,
+) -> Result<HttpResponse, Error> {
     let mut body = web::BytesMut::new();
     while let Some(chunk) = payload.next().await {
         let chunk = chunk?;
-        if chunk.len() + body.len() > MAX_SIZE {
-            return Err(error::ErrorBadRequest("overflow"));
+        if (chunk.len() + body.len()) as u64 > limit.0 {
+            return Ok(HttpResponse::PayloadTooLarge().json(json!({
+                "status": 413,
+                "success": false,
+                "msg": format!("Request body exceeds the upload limit of {} bytes", limit.0),
+            })));
         }
         body.extend_from_slice(&chunk);
     }
-    let data = Cursor::new(body);
-    let boxed = Box::new(data);
-    match client.add(*boxed).await {
-        Ok(res) => Ok(HttpResponse::Ok().json(IpfsResponse {
-            hash: res.hash,
-            name: res.name,
-            size: res.size,
-        })),
+    let size = body.len();
+    let mut span = req
+        .extensions()
+        .get::<RequestContext>()
+        .map(|context| context.span.client_span("ipfs.add"));
+    if let Some(span) = span.as_mut() {
+        span.set_attribute("ipfs.add_bytes", size as u64);
+    }
+    let result = node.add(body.to_vec()).await;
+    if let (Some(span), Err(_)) = (span.as_mut(), &result) {
+        span.set_error();
+    }
+    drop(span);
+    match result {
+        Ok(res) => {
+            metrics.ipfs_add_bytes.observe(size as f64);
+            Ok(HttpResponse::Ok().json(IpfsResponse {
+                hash: res.hash,
+                name: res.name,
+                size: res.size,
+            }))
+        }
         Err(e) => Err(error::ErrorInternalServerError(format!(
-            "Internal Server Error: {:?}",
+            "Internal Server Error: {}",
             e
         )))
     }
 }
+
+#[get("/metrics")]
+async fn get_metrics(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+#[get("/healthz")]
+async fn get_healthz() -> HttpResponse {
+    health::liveness()
+}
+
+#[get("/readyz")]
+async fn get_readyz(node: web::Data<IpfsNode>) -> HttpResponse {
+    let timeout = Duration::from_secs(2);
+    let ipfs = Check::run(timeout, node.version(timeout)).await;
+    health::readiness(vec![("ipfs", ipfs)])
+}
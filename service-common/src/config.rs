@@ -0,0 +1,185 @@
+use std::{
+    env,
+    fmt,
+    fs,
+};
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+};
+use toml::value::{
+    Table,
+    Value,
+};
+
+pub const REDACTED: &str = "<redacted>";
+
+/// Command line arguments, anything but `--config`, `--print-config` and the service's own
+/// switches overrides a setting
+pub struct Args {
+    env_prefix: &'static str,
+    pub config_path: Option<String>,
+    pub print_config: bool,
+    switches: Vec<String>,
+    overrides: Vec<(String, String)>,
+}
+
+impl Args {
+    /// Parse `--config PATH`, `--print-config`, the valueless `switches` and `--setting-name value`
+    /// or `--setting-name=value` overrides, for a service whose environment variables are named
+    /// `<env_prefix>_<SETTING>`
+    pub fn parse(
+        env_prefix: &'static str,
+        switches: &[&str],
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self, String> {
+        let mut parsed = Args {
+            env_prefix,
+            config_path: None,
+            print_config: false,
+            switches: Vec::new(),
+            overrides: Vec::new(),
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let flag = arg
+                .strip_prefix("--")
+                .ok_or_else(|| format!("unexpected argument {}", arg))?;
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (flag, None),
+            };
+            match name {
+                "print-config" => parsed.print_config = true,
+                _ if switches.contains(&name) => parsed.switches.push(name.to_string()),
+                _ => {
+                    let value = match value {
+                        Some(value) => value,
+                        None => args.next().ok_or_else(|| format!("--{} needs a value", name))?,
+                    };
+                    if name == "config" {
+                        parsed.config_path = Some(value);
+                    } else {
+                        parsed.overrides.push((name.replace('-', "_"), value));
+                    }
+                }
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Whether the valueless `--<name>` was given
+    pub fn switch(&self, name: &str) -> bool {
+        self.switches.iter().any(|switch| switch == name)
+    }
+
+    /// The TOML file from `--config`, or else `<env_prefix>_CONFIG`
+    pub fn config_file(&self) -> Option<String> {
+        self.config_path
+            .clone()
+            .or_else(|| env::var(format!("{}_CONFIG", self.env_prefix)).ok())
+    }
+
+    /// Layer the TOML file, `<env_prefix>_*` environment variables and command line overrides
+    /// over the defaults of `T`
+    pub fn load<T: Default + Serialize + DeserializeOwned>(&self) -> Result<T, String> {
+        let defaults = match Value::try_from(T::default()) {
+            Ok(Value::Table(defaults)) => defaults,
+            _ => unreachable!("the default configuration is a table"),
+        };
+        let mut merged = defaults.clone();
+
+        if let Some(path) = self.config_file() {
+            let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            let file: Table = toml::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+            for (key, value) in file {
+                let default = defaults
+                    .get(&key)
+                    .ok_or_else(|| format!("{}: unknown setting {}", path, key))?;
+                if value.type_str() != default.type_str() {
+                    return Err(format!("{}: {} must be {}", path, key, default.type_str()));
+                }
+                merged.insert(key, value);
+            }
+        }
+
+        for (key, default) in &defaults {
+            let name = format!("{}_{}", self.env_prefix, key.to_uppercase());
+            if let Ok(raw) = env::var(&name) {
+                let value = coerce(default, &raw).map_err(|e| format!("{}: {}", name, e))?;
+                merged.insert(key.clone(), value);
+            }
+        }
+
+        for (key, raw) in &self.overrides {
+            let flag = format!("--{}", key.replace('_', "-"));
+            let default = defaults.get(key).ok_or_else(|| format!("unknown option {}", flag))?;
+            let value = coerce(default, raw).map_err(|e| format!("{}: {}", flag, e))?;
+            merged.insert(key.clone(), value);
+        }
+
+        Value::Table(merged).try_into().map_err(|e| e.to_string())
+    }
+}
+
+/// Convert an environment variable or flag to the type of the setting's default value,
+/// lists are comma separated
+fn coerce(default: &Value, raw: &str) -> Result<Value, String> {
+    match default {
+        Value::Integer(_) => raw
+            .trim()
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("expected an integer, got {:?}", raw)),
+        Value::Boolean(_) => raw
+            .trim()
+            .parse()
+            .map(Value::Boolean)
+            .map_err(|_| format!("expected true or false, got {:?}", raw)),
+        Value::Array(_) => Ok(Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect(),
+        )),
+        _ => Ok(Value::String(raw.to_string())),
+    }
+}
+
+/// `host:port`, where the host is a name or an IP address
+pub fn is_address(address: &str) -> bool {
+    address
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+}
+
+/// `uri` with any `user:password@` replaced, so rendered URIs don't leak credentials
+pub fn redact_userinfo(uri: &str) -> String {
+    let (scheme, rest) = match uri.split_once("://") {
+        Some(parts) => parts,
+        None => return uri.to_string(),
+    };
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    match rest[..authority_end].rfind('@') {
+        Some(at) => format!("{}://{}{}", scheme, REDACTED, &rest[at..]),
+        None => uri.to_string(),
+    }
+}
+
+/// `None` for settings left empty
+pub fn optional(value: &str) -> Option<&str> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Report configuration problems, one per line, and stop before anything starts
+pub fn exit_with_error(error: impl fmt::Display) -> ! {
+    for line in error.to_string().lines() {
+        eprintln!("Configuration error: {}", line);
+    }
+    std::process::exit(2)
+}
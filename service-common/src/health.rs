@@ -0,0 +1,78 @@
+use std::{
+    fmt::Display,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use actix_web::{
+    http::StatusCode,
+    rt,
+    HttpResponse,
+};
+use futures::Future;
+use serde::Serialize;
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+
+/// Outcome of checking one dependency
+#[derive(Serialize)]
+pub struct Check {
+    pub healthy: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    /// Run `check`, failing it once `timeout` has passed
+    pub async fn run<F, T, E>(timeout: Duration, check: F) -> Self
+    where
+        F: Future<Output = Result<T, E>>,
+        E: Display,
+    {
+        let started_at = Instant::now();
+        let error = match rt::time::timeout(timeout, check).await {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("timed out after {:?}", timeout)),
+        };
+        Check {
+            healthy: error.is_none(),
+            latency_ms: started_at.elapsed().as_millis() as u64,
+            error,
+        }
+    }
+}
+
+/// The process is up and serving requests
+pub fn liveness() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": 200,
+        "success": true,
+        "payload": {
+            "alive": true,
+        },
+    }))
+}
+
+/// 200 when every dependency is healthy, 503 otherwise
+pub fn readiness(checks: Vec<(&str, Check)>) -> HttpResponse {
+    let ready = checks.iter().all(|(_, check)| check.healthy);
+    let checks: Map<String, Value> = checks
+        .into_iter()
+        .map(|(name, check)| (name.to_string(), serde_json::to_value(check).unwrap()))
+        .collect();
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    HttpResponse::build(status).json(json!({
+        "status": status.as_u16(),
+        "success": ready,
+        "payload": {
+            "ready": ready,
+            "checks": checks,
+        },
+    }))
+}
@@ -0,0 +1,6 @@
+//! Configuration, TLS, tracing, health and metrics plumbing shared by the proxy and the services
+pub mod config;
+pub mod health;
+pub mod metrics;
+pub mod tls;
+pub mod trace;
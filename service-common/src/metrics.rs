@@ -0,0 +1,165 @@
+use std::{
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::Instant,
+};
+use actix_web::{
+    dev::{
+        Service,
+        ServiceRequest,
+        ServiceResponse,
+        Transform,
+    },
+    Error,
+};
+use futures::{
+    future::{
+        ok,
+        Ready,
+    },
+    Future,
+};
+use prometheus::{
+    core::Collector,
+    Encoder,
+    HistogramOpts,
+    HistogramVec,
+    IntCounterVec,
+    Opts,
+    Registry,
+    TextEncoder,
+};
+
+/// Prometheus registry holding the HTTP collectors every service has, rendered on `/metrics`
+pub struct HttpMetrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_duration: HistogramVec,
+}
+
+impl Default for HttpMetrics {
+    fn default() -> Self {
+        let metrics = HttpMetrics {
+            registry: Registry::new(),
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests handled"),
+                &["route", "method", "status"],
+            ).unwrap(),
+            http_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time until response headers were ready"),
+                &["route", "method", "status"],
+            ).unwrap(),
+        };
+        metrics.register(metrics.http_requests.clone());
+        metrics.register(metrics.http_duration.clone());
+        metrics
+    }
+}
+
+impl HttpMetrics {
+    /// Add a service's own collector, returning it for the service to keep
+    pub fn register<C: Collector + Clone + 'static>(&self, collector: C) -> C {
+        self.registry.register(Box::new(collector.clone())).unwrap();
+        collector
+    }
+
+    /// Everything registered, in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// Counts and times every request by route pattern, method and status.
+/// Requests that matched no route pattern are labelled with `fallback_route`
+pub struct RequestMetrics {
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    fallback_route: &'static str,
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: &HttpMetrics, fallback_route: &'static str) -> Self {
+        RequestMetrics {
+            http_requests: metrics.http_requests.clone(),
+            http_duration: metrics.http_duration.clone(),
+            fallback_route,
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RequestMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware {
+            service,
+            http_requests: self.http_requests.clone(),
+            http_duration: self.http_duration.clone(),
+            fallback_route: self.fallback_route,
+        })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    fallback_route: &'static str,
+    service: S,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started_at = Instant::now();
+        let method = req.method().as_str().to_string();
+        let fut = self.service.call(req);
+        let http_requests = self.http_requests.clone();
+        let http_duration = self.http_duration.clone();
+        let fallback_route = self.fallback_route;
+
+        Box::pin(async move {
+            let result = fut.await;
+            let (route, status) = match &result {
+                Ok(res) => (res.request().match_pattern(), res.status()),
+                Err(e) => (None, e.as_response_error().status_code()),
+            };
+            let route = route.unwrap_or_else(|| fallback_route.to_string());
+            let labels = [route.as_str(), method.as_str(), status.as_str()];
+            http_requests.with_label_values(&labels).inc();
+            http_duration
+                .with_label_values(&labels)
+                .observe(started_at.elapsed().as_secs_f64());
+            result
+        })
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    fs::{
+        self,
+        File,
+    },
+    io::{
+        self,
+        BufReader,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+use actix_web::{
+    client::{
+        ClientBuilder,
+        Connector,
+    },
+    rt,
+};
+use rustls::{
+    internal::pemfile,
+    sign::{
+        self,
+        CertifiedKey,
+    },
+    ClientConfig,
+    ClientHello,
+    NoClientAuth,
+    ResolvesServerCert,
+    ServerConfig,
+};
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| invalid(format!("{} is not a PEM certificate chain", cert_path.display())))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("{} contains no certificates", cert_path.display())));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| invalid(format!("{} is not a PEM private key", key_path.display())))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|_| invalid(format!("{} is not a PEM private key", key_path.display())))?;
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| invalid(format!("{} contains no private key", key_path.display())))?;
+    let key = sign::any_supported_type(key)
+        .map_err(|_| invalid(format!("{} holds an unsupported key type", key_path.display())))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Server certificate that is read again when its files change or the process gets SIGHUP,
+/// new handshakes pick it up without a restart
+pub struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<CertifiedKey>,
+    loaded: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadingCert {
+    pub fn load(cert_path: &Path, key_path: &Path) -> io::Result<Arc<Self>> {
+        let loaded = (modified(cert_path), modified(key_path));
+        Ok(Arc::new(ReloadingCert {
+            current: RwLock::new(read_certified_key(cert_path, key_path)?),
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            loaded: Mutex::new(loaded),
+        }))
+    }
+
+    /// Swap in the certificate on disk, keeping the old one if the new one doesn't load
+    fn reload(&self) {
+        *self.loaded.lock().unwrap() = (modified(&self.cert_path), modified(&self.key_path));
+        match read_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = key;
+                println!("Reloaded TLS certificate {}", self.cert_path.display());
+            }
+            Err(e) => println!("Error reloading TLS certificate {}: {}", self.cert_path.display(), e),
+        }
+    }
+
+    fn changed(&self) -> bool {
+        *self.loaded.lock().unwrap() != (modified(&self.cert_path), modified(&self.key_path))
+    }
+
+    /// Check the files every `interval` and reload on SIGHUP, must run on the actix system
+    pub fn watch(self: Arc<Self>, interval: Duration) {
+        let watched = self.clone();
+        rt::spawn(async move {
+            let mut ticker = rt::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if watched.changed() {
+                    watched.reload();
+                }
+            }
+        });
+        rt::spawn(async move {
+            let mut hangup = match rt::signal::unix::signal(rt::signal::unix::SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    println!("Error listening for SIGHUP: {}", e);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                self.reload();
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Listener configuration serving whatever certificate `cert` currently holds
+pub fn server_config(cert: Arc<ReloadingCert>) -> ServerConfig {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = cert;
+    config
+}
+
+/// Client configuration trusting only the certificates in a PEM bundle
+pub fn client_config(ca_bundle: &Path) -> io::Result<Arc<ClientConfig>> {
+    let mut config = ClientConfig::new();
+    let (added, _) = config
+        .root_store
+        .add_pem_file(&mut BufReader::new(File::open(ca_bundle)?))
+        .map_err(|_| invalid(format!("{} is not a PEM CA bundle", ca_bundle.display())))?;
+    if added == 0 {
+        return Err(invalid(format!("{} contains no CA certificates", ca_bundle.display())));
+    }
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    Ok(Arc::new(config))
+}
+
+/// Client builder that verifies servers against `tls` when given, the default roots otherwise
+pub fn client_builder(tls: Option<&Arc<ClientConfig>>) -> ClientBuilder {
+    match tls {
+        Some(tls) => ClientBuilder::new().connector(Connector::new().rustls(tls.clone()).finish()),
+        None => ClientBuilder::new(),
+    }
+}
+
+/// Like `client_builder`, giving up on connecting after `connect_timeout`
+pub fn client_builder_with_connect_timeout(tls: Option<&Arc<ClientConfig>>, connect_timeout: Duration) -> ClientBuilder {
+    let connector = Connector::new().timeout(connect_timeout);
+    let connector = match tls {
+        Some(tls) => connector.rustls(tls.clone()),
+        None => connector,
+    };
+    ClientBuilder::new().connector(connector.finish())
+}
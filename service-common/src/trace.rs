@@ -0,0 +1,465 @@
+use std::{
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use actix_web::{
+    client::Client,
+    dev::{
+        Service,
+        ServiceRequest,
+        ServiceResponse,
+        Transform,
+    },
+    http::{
+        header::{
+            HeaderName,
+            HeaderValue,
+        },
+        HeaderMap,
+    },
+    error::InternalError,
+    rt,
+    Error,
+    HttpMessage,
+};
+use futures::{
+    channel::mpsc,
+    future::{
+        ok,
+        Ready,
+    },
+    Future,
+    StreamExt,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use uuid::Uuid;
+
+pub const REQUEST_ID: &str = "x-request-id";
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Where finished spans are sent
+#[derive(Clone, Debug)]
+pub enum TraceExport {
+    None,
+    /// One JSON line per span
+    Stdout,
+    /// OTLP/HTTP JSON, e.g. `http://127.0.0.1:4318/v1/traces`
+    Otlp(String),
+}
+
+impl TraceExport {
+    /// Parse `none`, `stdout` or `otlp`, spans are posted to `otlp_endpoint`
+    pub fn parse(name: &str, otlp_endpoint: &str) -> Option<Self> {
+        match name {
+            "none" => Some(TraceExport::None),
+            "stdout" => Some(TraceExport::Stdout),
+            "otlp" => Some(TraceExport::Otlp(otlp_endpoint.to_string())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SpanKind {
+    Server,
+    Client,
+}
+
+struct SpanData {
+    name: &'static str,
+    kind: SpanKind,
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+impl SpanData {
+    /// The span in the OTLP JSON encoding
+    fn to_otlp(&self) -> Value {
+        let attributes: Vec<Value> = self.attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::Bool(b) => json!({ "boolValue": b }),
+                    Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+                    Value::Number(n) => json!({ "doubleValue": n }),
+                    Value::String(s) => json!({ "stringValue": s }),
+                    other => json!({ "stringValue": other.to_string() }),
+                };
+                json!({ "key": key, "value": value })
+            })
+            .collect();
+        json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "parentSpanId": self.parent_span_id.clone().unwrap_or_default(),
+            "name": self.name,
+            "kind": match self.kind {
+                SpanKind::Server => 2,
+                SpanKind::Client => 3,
+            },
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(self.end).to_string(),
+            "attributes": attributes,
+            "status": { "code": if self.error { 2 } else { 0 } },
+        })
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+fn random_id(bytes: usize) -> String {
+    hex::encode(&Uuid::new_v4().as_bytes()[..bytes])
+}
+
+/// Handle to the background task that exports finished spans in batches
+#[derive(Clone)]
+pub struct Tracer {
+    sender: Option<mpsc::Sender<SpanData>>,
+}
+
+impl Tracer {
+    /// Start the export task, must be called on the actix system
+    pub fn start(export: TraceExport, service_name: &'static str, batch_size: usize, flush_interval: Duration) -> Self {
+        if let TraceExport::None = export {
+            return Tracer {
+                sender: None,
+            };
+        }
+        let (sender, receiver) = mpsc::channel(batch_size * 10);
+        rt::spawn(export_spans(receiver, export, service_name, batch_size, flush_interval));
+        Tracer {
+            sender: Some(sender),
+        }
+    }
+
+    /// Start a span for an incoming request, continuing the caller's trace if it sent a `traceparent`
+    pub fn server_span(&self, name: &'static str, headers: &HeaderMap) -> Span {
+        let parent = headers
+            .get(TRACEPARENT)
+            .and_then(|h| h.to_str().ok())
+            .and_then(parse_traceparent);
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (random_id(16), None),
+        };
+        Span::new(self.clone(), name, SpanKind::Server, trace_id, parent_span_id)
+    }
+
+    fn export(&self, span: SpanData) {
+        if let Some(sender) = &self.sender {
+            // Tracing is best effort, spans are dropped while the exporter is behind
+            let _ = sender.clone().try_send(span);
+        }
+    }
+}
+
+/// `(trace_id, span_id)` of a W3C `traceparent` header
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    if parts.len() < 4 || parts[1].len() != 32 || parts[2].len() != 16 {
+        return None;
+    }
+    let is_id = |id: &str| id.chars().all(|c| c.is_ascii_hexdigit()) && id.chars().any(|c| c != '0');
+    if !is_id(parts[1]) || !is_id(parts[2]) {
+        return None;
+    }
+    Some((parts[1].to_lowercase(), parts[2].to_lowercase()))
+}
+
+/// Enough of a span to start children of it, cheap to clone into request extensions
+#[derive(Clone)]
+pub struct SpanContext {
+    tracer: Tracer,
+    trace_id: String,
+    span_id: String,
+}
+
+impl SpanContext {
+    /// Start a span for an outgoing call made on behalf of this one
+    pub fn client_span(&self, name: &'static str) -> Span {
+        Span::new(self.tracer.clone(), name, SpanKind::Client, self.trace_id.clone(), Some(self.span_id.clone()))
+    }
+
+    pub fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+}
+
+/// A timed operation, exported when dropped
+pub struct Span {
+    context: SpanContext,
+    data: Option<SpanData>,
+    started_at: Instant,
+}
+
+impl Span {
+    fn new(tracer: Tracer, name: &'static str, kind: SpanKind, trace_id: String, parent_span_id: Option<String>) -> Self {
+        let span_id = random_id(8);
+        Span {
+            data: Some(SpanData {
+                name,
+                kind,
+                trace_id: trace_id.clone(),
+                span_id: span_id.clone(),
+                parent_span_id,
+                start: SystemTime::now(),
+                end: SystemTime::now(),
+                attributes: Vec::new(),
+                error: false,
+            }),
+            context: SpanContext {
+                tracer,
+                trace_id,
+                span_id,
+            },
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn context(&self) -> &SpanContext {
+        &self.context
+    }
+
+    /// W3C `traceparent` value that makes the receiver's spans children of this one
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.context.trace_id, self.context.span_id)
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if let Some(data) = self.data.as_mut() {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn set_error(&mut self) {
+        if let Some(data) = self.data.as_mut() {
+            data.error = true;
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end = data.start + self.started_at.elapsed();
+            self.context.tracer.export(data);
+        }
+    }
+}
+
+async fn export_spans(
+    mut receiver: mpsc::Receiver<SpanData>,
+    export: TraceExport,
+    service_name: &'static str,
+    batch_size: usize,
+    flush_interval: Duration,
+) {
+    let client = Client::builder().timeout(Duration::from_secs(10)).finish();
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut deadline = Instant::now() + flush_interval;
+    loop {
+        let span = if batch.is_empty() {
+            receiver.next().await
+        } else {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match rt::time::timeout(remaining, receiver.next()).await {
+                Ok(span) => span,
+                Err(_) => {
+                    flush_spans(&client, &export, service_name, &mut batch).await;
+                    continue;
+                }
+            }
+        };
+
+        match span {
+            Some(span) => {
+                if batch.is_empty() {
+                    deadline = Instant::now() + flush_interval;
+                }
+                batch.push(span);
+                if batch.len() >= batch_size {
+                    flush_spans(&client, &export, service_name, &mut batch).await;
+                }
+            }
+            None => {
+                flush_spans(&client, &export, service_name, &mut batch).await;
+                return;
+            }
+        }
+    }
+}
+
+async fn flush_spans(client: &Client, export: &TraceExport, service_name: &str, batch: &mut Vec<SpanData>) {
+    let spans: Vec<Value> = batch.drain(..).map(|span| span.to_otlp()).collect();
+    match export {
+        TraceExport::None => {}
+        TraceExport::Stdout => {
+            for span in &spans {
+                println!("{}", span);
+            }
+        }
+        TraceExport::Otlp(endpoint) => {
+            let body = json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }],
+                    },
+                    "scopeSpans": [{ "scope": { "name": service_name }, "spans": spans }],
+                }]
+            });
+            match client.post(endpoint.as_str()).send_json(&body).await {
+                Ok(res) if res.status().is_success() => {}
+                Ok(res) => println!("Error exporting spans to {}: {}", endpoint, res.status()),
+                Err(e) => println!("Error exporting spans to {}: {}", endpoint, e),
+            }
+        }
+    }
+}
+
+/// Request ID and trace of the request being handled, stored in its extensions
+#[derive(Clone)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub span: SpanContext,
+}
+
+impl RequestContext {
+    /// Headers that carry the request ID and trace to a downstream call made under `span`
+    pub fn propagation_headers(&self, span: &Span) -> [(&'static str, String); 2] {
+        [
+            (REQUEST_ID, self.request_id.clone()),
+            (TRACEPARENT, span.traceparent()),
+        ]
+    }
+}
+
+/// Use the client's `X-Request-Id` when it is reasonable, otherwise make one up
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID)
+        .and_then(|h| h.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_graphic()))
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_hyphenated().to_string())
+}
+
+fn echo_request_id(headers: &mut HeaderMap, request_id: &str) {
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        headers.insert(HeaderName::from_static(REQUEST_ID), value);
+    }
+}
+
+/// Assigns every request an ID and a server span named `span_name`, and echoes the ID in the response
+pub struct RequestTracing {
+    tracer: Tracer,
+    span_name: &'static str,
+}
+
+impl RequestTracing {
+    pub fn new(tracer: Tracer, span_name: &'static str) -> Self {
+        RequestTracing {
+            tracer,
+            span_name,
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RequestTracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware {
+            service,
+            tracer: self.tracer.clone(),
+            span_name: self.span_name,
+        })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    tracer: Tracer,
+    span_name: &'static str,
+    service: S,
+}
+
+impl<S, B> Service for RequestTracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let request_id = request_id(req.headers());
+        let mut span = self.tracer.server_span(self.span_name, req.headers());
+        span.set_attribute("http.method", req.method().as_str());
+        span.set_attribute("http.target", req.path());
+        span.set_attribute("request.id", request_id.as_str());
+        req.extensions_mut().insert(RequestContext {
+            request_id: request_id.clone(),
+            span: span.context().clone(),
+        });
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let result = fut.await;
+            let status = match &result {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            span.set_attribute("http.status_code", status.as_u16());
+            if status.is_server_error() {
+                span.set_error();
+            }
+            match result {
+                Ok(mut res) => {
+                    echo_request_id(res.headers_mut(), &request_id);
+                    Ok(res)
+                }
+                // Rejections from inner middleware become responses here so they carry the ID too
+                Err(e) => {
+                    let mut res = e.as_response_error().error_response();
+                    echo_request_id(res.headers_mut(), &request_id);
+                    Err(InternalError::from_response(e, res).into())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,122 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+pub use service_common::config::{
+    exit_with_error,
+    optional,
+    Args,
+};
+use service_common::config::{
+    is_address,
+    redact_userinfo,
+    REDACTED,
+};
+use super::trace::TraceExport;
+
+/// Environment variables are named `SIMPLEAPI_<SETTING>`, e.g. `SIMPLEAPI_LISTEN_ADDRESS`
+pub const ENV_PREFIX: &str = "SIMPLEAPI";
+
+/// Key service settings, layered from defaults, a TOML file, `SIMPLEAPI_*` environment variables
+/// and `--setting-name value` flags, each overriding the one before
+///
+/// Empty strings leave optional settings such as `tls_cert` unset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen_address: String,
+    pub tls_cert: String,
+    pub tls_key: String,
+    pub tls_reload_interval_secs: u64,
+    pub mongodb_uri: String,
+    pub mongodb_database: String,
+    pub proxy_addresses: Vec<String>,
+    pub proxy_admin_token: String,
+    pub proxy_ca_bundle: String,
+    pub trace_exporter: String,
+    pub trace_otlp_endpoint: String,
+    pub trace_batch_size: usize,
+    pub trace_flush_interval_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_address: "127.0.0.1:5002".to_string(),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            tls_reload_interval_secs: 30,
+            mongodb_uri: "mongodb://localhost:27017".to_string(),
+            mongodb_database: "secure".to_string(),
+            proxy_addresses: Vec::new(),
+            proxy_admin_token: String::new(),
+            proxy_ca_bundle: String::new(),
+            trace_exporter: "none".to_string(),
+            trace_otlp_endpoint: "http://127.0.0.1:4318/v1/traces".to_string(),
+            trace_batch_size: 100,
+            trace_flush_interval_secs: 5,
+        }
+    }
+}
+
+impl Config {
+    /// Layer the TOML file from `--config` or `SIMPLEAPI_CONFIG`, `SIMPLEAPI_*` environment
+    /// variables and command line overrides over the defaults
+    pub fn load(args: &Args) -> Result<Self, String> {
+        args.load()
+    }
+
+    /// Check every setting, reporting all problems at once
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        if !is_address(&self.listen_address) {
+            errors.push(format!("listen_address must be host:port, got {:?}", self.listen_address));
+        }
+        if self.tls_cert.is_empty() != self.tls_key.is_empty() {
+            errors.push("tls_cert and tls_key must be set together".to_string());
+        }
+        if !self.mongodb_uri.starts_with("mongodb://") && !self.mongodb_uri.starts_with("mongodb+srv://") {
+            errors.push(format!("mongodb_uri must be a mongodb:// or mongodb+srv:// URI, got {:?}", self.mongodb_uri));
+        }
+        if self.mongodb_database.is_empty() {
+            errors.push("mongodb_database must not be empty".to_string());
+        }
+        for address in &self.proxy_addresses {
+            let valid = ["http://", "https://"]
+                .iter()
+                .any(|scheme| address.strip_prefix(scheme).is_some_and(is_address));
+            if !valid {
+                errors.push(format!("proxy_addresses must be http(s)://host:port, got {:?}", address));
+            }
+        }
+        if !self.proxy_addresses.is_empty() && self.proxy_admin_token.is_empty() {
+            errors.push("proxy_admin_token must be set to invalidate keys at proxy_addresses".to_string());
+        }
+        if TraceExport::parse(&self.trace_exporter, &self.trace_otlp_endpoint).is_none() {
+            errors.push("trace_exporter must be none, stdout or otlp".to_string());
+        }
+        if !self.trace_otlp_endpoint.starts_with("http://") && !self.trace_otlp_endpoint.starts_with("https://") {
+            errors.push(format!("trace_otlp_endpoint must be a URL, got {:?}", self.trace_otlp_endpoint));
+        }
+        if self.trace_batch_size == 0 {
+            errors.push("trace_batch_size must be at least 1".to_string());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// The effective configuration as TOML, with secrets redacted
+    pub fn render(&self) -> String {
+        let mut shown = self.clone();
+        if !shown.proxy_admin_token.is_empty() {
+            shown.proxy_admin_token = REDACTED.to_string();
+        }
+        shown.mongodb_uri = redact_userinfo(&shown.mongodb_uri);
+        shown.trace_otlp_endpoint = redact_userinfo(&shown.trace_otlp_endpoint);
+        shown.proxy_addresses = shown.proxy_addresses.iter().map(|address| redact_userinfo(address)).collect();
+        toml::to_string(&shown).expect("the configuration serializes to TOML")
+    }
+}
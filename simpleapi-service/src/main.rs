@@ -1,3 +1,4 @@
+use std::time::Duration;
 use actix_web::{
     self,
     web,
@@ -7,19 +8,42 @@ use mongodb::{
     options::ClientOptions,
     Client,
 };
+use config::{
+    Args,
+    Config,
+};
 use processor::ApiKeyProcessor;
+use notifier::CacheInvalidator;
+use tls::ReloadingCert;
+use metrics::Metrics;
+use service_common::{
+    health,
+    metrics::RequestMetrics,
+    tls,
+    trace,
+};
+use trace::{
+    RequestTracing,
+    TraceExport,
+    Tracer,
+};
 
+pub mod config;
 pub mod routes;
 pub mod processor;
+pub mod notifier;
+pub mod metrics;
 
 struct Container {
     key: ApiKeyProcessor,
+    invalidator: CacheInvalidator,
 }
 
 impl Container {
-    fn create(key:ApiKeyProcessor) -> Self {
+    fn create(key:ApiKeyProcessor, invalidator: CacheInvalidator) -> Self {
         Container {
-            key
+            key,
+            invalidator,
         }
     }
 }
@@ -30,24 +54,64 @@ struct State {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let address = "127.0.0.1:5002";
-    let mongodb_address = "mongodb://localhost:27017";
-    let mongodb_name = "secure";
-    let options = ClientOptions::parse(mongodb_address)
+    let args = Args::parse(config::ENV_PREFIX, &[], std::env::args().skip(1))
+        .unwrap_or_else(|e| config::exit_with_error(e));
+    let config = Config::load(&args)
+        .and_then(|config| config.validate().map(|_| config))
+        .unwrap_or_else(|e| config::exit_with_error(e));
+    if args.print_config {
+        print!("{}", config.render());
+        return Ok(());
+    }
+
+    let proxy_addresses = config.proxy_addresses.clone();
+    if proxy_addresses.is_empty() {
+        println!("proxy_addresses is not set, changed keys take effect once the proxies' auth cache expires");
+    }
+    let proxy_admin_token = config::optional(&config.proxy_admin_token).map(str::to_string);
+    let proxy_tls = config::optional(&config.proxy_ca_bundle).map(|path| {
+        tls::client_config(path.as_ref())
+            .unwrap_or_else(|e| config::exit_with_error(format!("proxy_ca_bundle must be a PEM CA bundle: {}", e)))
+    });
+    let options = ClientOptions::parse(&config.mongodb_uri)
         .await
-        .unwrap();
+        .unwrap_or_else(|e| config::exit_with_error(format!("mongodb_uri: {}", e)));
     let client = Client::with_options(options)
-        .unwrap();
-    let database = client.database(mongodb_name);
+        .unwrap_or_else(|e| config::exit_with_error(format!("mongodb_uri: {}", e)));
+    let database = client.database(&config.mongodb_database);
     let keys = database.collection("keys");
+    let database = web::Data::new(database);
+
+    let metrics = web::Data::new(Metrics::default());
+    let trace_export = TraceExport::parse(&config.trace_exporter, &config.trace_otlp_endpoint)
+        .expect("trace_exporter must be none, stdout or otlp");
+    let tracer = Tracer::start(
+        trace_export,
+        "simpleapi-service",
+        config.trace_batch_size,
+        Duration::from_secs(config.trace_flush_interval_secs),
+    );
 
-    print!("SimpleAPI keys Listening {} ...", address);
+    print!("SimpleAPI keys Listening {} ...", config.listen_address);
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let container = Container::create(
-            ApiKeyProcessor::create(keys.clone())
+            ApiKeyProcessor::create(keys.clone(), metrics.clone().into_inner()),
+            CacheInvalidator::create(
+                proxy_addresses.clone(),
+                proxy_admin_token.clone(),
+                proxy_tls.as_ref(),
+                metrics.clone().into_inner(),
+            ),
         );
         actix_web::App::new()
+            .wrap(RequestMetrics::new(&metrics.http, "unmatched"))
+            .wrap(RequestTracing::new(tracer.clone(), "simpleapi.request"))
+            .app_data(metrics.clone())
+            .app_data(database.clone())
+            .service(routes::get_healthz)
+            .service(routes::get_readyz)
+            .service(routes::get_metrics)
             .service(
                 web::scope("/keys")
                 .data(State{
@@ -59,8 +123,17 @@ async fn main() -> std::io::Result<()> {
                 .service(routes::update)
                 .service(routes::delete),
             )
-    })
-    .bind(address)?
-    .run()
-    .await
+    });
+    let server = match (config::optional(&config.tls_cert), config::optional(&config.tls_key)) {
+        (Some(cert), Some(key)) => {
+            let cert = ReloadingCert::load(cert.as_ref(), key.as_ref())?;
+            cert.clone().watch(Duration::from_secs(config.tls_reload_interval_secs));
+            server.bind_rustls(&config.listen_address, tls::server_config(cert))?
+        }
+        (None, None) => server.bind(&config.listen_address)?,
+        _ => unreachable!("tls_cert and tls_key are validated together"),
+    };
+    server
+        .run()
+        .await
 }
@@ -0,0 +1,47 @@
+use std::time::Instant;
+use prometheus::{
+    HistogramOpts,
+    HistogramVec,
+    IntCounterVec,
+    Opts,
+};
+use service_common::metrics::HttpMetrics;
+
+/// Prometheus collectors for the keys service, rendered on `/metrics`
+pub struct Metrics {
+    pub http: HttpMetrics,
+    pub mongodb_duration: HistogramVec,
+    pub cache_invalidations: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let http = HttpMetrics::default();
+        Metrics {
+            mongodb_duration: http.register(HistogramVec::new(
+                HistogramOpts::new("mongodb_operation_duration_seconds", "MongoDB operations"),
+                &["operation", "outcome"],
+            ).unwrap()),
+            cache_invalidations: http.register(IntCounterVec::new(
+                Opts::new("cache_invalidations_total", "Key invalidations pushed to the proxies"),
+                &["outcome"],
+            ).unwrap()),
+            http,
+        }
+    }
+}
+
+impl Metrics {
+    /// Record how long a MongoDB operation took and whether it failed
+    pub fn observe_mongodb<T, E>(&self, operation: &str, started_at: Instant, result: &Result<T, E>) {
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        self.mongodb_duration
+            .with_label_values(&[operation, outcome])
+            .observe(started_at.elapsed().as_secs_f64());
+    }
+
+    /// Everything registered, in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.http.render()
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+use actix_web::{
+    client::Client,
+    http::header,
+    rt,
+};
+use rustls::ClientConfig;
+use super::metrics::Metrics;
+use super::tls::client_builder;
+
+/// Pushes key invalidations to the proxies, so a disabled or deleted key
+/// stops working before its cached validation expires
+#[derive(Clone)]
+pub struct CacheInvalidator {
+    client: Client,
+    proxy_addresses: Vec<String>,
+    admin_token: Option<String>,
+    metrics: Arc<Metrics>,
+}
+
+impl CacheInvalidator {
+    /// `admin_token` must match the proxies' `admin_token`, `tls` verifies `https` proxies
+    pub fn create(
+        proxy_addresses: Vec<String>,
+        admin_token: Option<String>,
+        tls: Option<&Arc<ClientConfig>>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        CacheInvalidator {
+            client: client_builder(tls).finish(),
+            proxy_addresses,
+            admin_token,
+            metrics,
+        }
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        for proxy_address in &self.proxy_addresses {
+            let url = format!("{}/auth-cache/{}", proxy_address, key);
+            let mut request = self.client.delete(url.as_str());
+            if let Some(admin_token) = &self.admin_token {
+                request = request.header(header::AUTHORIZATION, admin_token.as_str());
+            }
+            let metrics = self.metrics.clone();
+            rt::spawn(async move {
+                // A proxy that refuses, e.g. with 401 for a wrong admin token, keeps serving the key
+                let outcome = match request.send().await {
+                    Ok(response) if response.status().is_success() => "ok",
+                    Ok(response) => {
+                        println!("Proxy at {} refused to invalidate key: {}", url, response.status());
+                        "rejected"
+                    }
+                    Err(e) => {
+                        println!("Error invalidating key at {}: {}", url, e);
+                        "error"
+                    }
+                };
+                metrics.cache_invalidations.with_label_values(&[outcome]).inc();
+            });
+        }
+    }
+}
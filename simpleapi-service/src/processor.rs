@@ -20,6 +20,7 @@ use futures::StreamExt;
 use uuid::Uuid;
 use serde::{
     Deserialize, 
+    Deserializer,
     Serialize
 };
 use actix_web::{
@@ -27,11 +28,16 @@ use actix_web::{
     HttpResponse, 
     ResponseError
 };
-use std::fmt::{
-    Display, 
-    Formatter, 
-    Result as FmtResult
+use std::{
+    fmt::{
+        Display, 
+        Formatter, 
+        Result as FmtResult
+    },
+    sync::Arc,
+    time::Instant,
 };
+use super::metrics::Metrics;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Key {
     #[serde(rename = "_id")]
@@ -40,6 +46,12 @@ pub struct Key {
     create_time: DateTime<Utc>,
     update_time: DateTime<Utc>,
     enabled: bool,
+    /// Browser origins the key may be used from, any origin when unset
+    allowed_origins: Option<Vec<String>>,
+    /// Endpoint policy scope applied by the proxy, the global rules when unset
+    scope: Option<String>,
+    /// Largest request body the proxy accepts for the key, the scope's or the endpoint policy's `max_upload_bytes` when unset
+    max_upload_bytes: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
@@ -47,10 +59,28 @@ pub struct NewKey {
     pub key: Uuid,
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UpdateKey {
     pub key: Uuid,
     pub enabled: bool,
+    /// Left unchanged when omitted, cleared by `null`
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub allowed_origins: Option<Option<Vec<String>>>,
+    /// Left unchanged when omitted, cleared by `null`
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub scope: Option<Option<String>>,
+    /// Left unchanged when omitted, cleared by `null`
+    #[serde(default, deserialize_with = "nullable", skip_serializing_if = "Option::is_none")]
+    pub max_upload_bytes: Option<Option<u64>>,
+}
+
+/// Tell an explicit `null`, `Some(None)`, apart from an omitted field, `None`
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
 }
 
 impl Key {
@@ -61,6 +91,11 @@ impl Key {
             create_time: *bson_doc.get_datetime("create_time")?,
             update_time: *bson_doc.get_datetime("update_time")?,
             enabled: bson_doc.get_bool("enabled")?,
+            allowed_origins: bson_doc.get_array("allowed_origins").ok().map(|origins| {
+                origins.iter().filter_map(|origin| origin.as_str().map(str::to_string)).collect()
+            }),
+            scope: bson_doc.get_str("scope").ok().map(str::to_string),
+            max_upload_bytes: bson_doc.get_i64("max_upload_bytes").ok().map(|max| max as u64),
         };
         Ok(key)
     }
@@ -115,8 +150,8 @@ impl From<SimpleApiError> for JsonError {
         };
 
         JsonError {
-            msg: format!("{}", err.to_string()),
-            status: status,
+            msg: err.to_string(),
+            status,
             success: false,
         }
     }
@@ -126,12 +161,14 @@ impl From<SimpleApiError> for JsonError {
 #[derive(Clone)]
 pub struct ApiKeyProcessor {
     collection: Collection,
+    metrics: Arc<Metrics>,
 }
 
 impl ApiKeyProcessor {
-    pub fn create(collection: Collection) -> Self {
+    pub fn create(collection: Collection, metrics: Arc<Metrics>) -> Self {
         ApiKeyProcessor {
             collection,
+            metrics,
         }
     }
 
@@ -142,25 +179,64 @@ impl ApiKeyProcessor {
             "create_time": Utc::now(),
             "enabled": true,
         };
-        let result = self.collection.insert_one(doc, None).await?;
-        Ok(result)
+        let started_at = Instant::now();
+        let result = self.collection.insert_one(doc, None).await;
+        self.metrics.observe_mongodb("insert_one", started_at, &result);
+        Ok(result?)
     }
-    pub async fn update(&self, key: UpdateKey) -> Result<UpdateResult, SimpleApiError> {
+    pub async fn update(&self, key: &UpdateKey) -> Result<UpdateResult, SimpleApiError> {
         let filter = doc! {
             "key": key.key.to_hyphenated().to_string(),
         };
-        let doc = doc!{
-            "$set": {
-                "update_time": Utc::now(),
-                "enabled": key.enabled,
+        let mut set = doc! {
+            "update_time": Utc::now(),
+            "enabled": key.enabled,
+        };
+        let mut unset = Document::new();
+        match &key.allowed_origins {
+            Some(Some(allowed_origins)) => {
+                set.insert("allowed_origins", allowed_origins.clone());
             }
+            Some(None) => {
+                unset.insert("allowed_origins", "");
+            }
+            None => {}
+        }
+        match &key.scope {
+            Some(Some(scope)) => {
+                set.insert("scope", scope.clone());
+            }
+            Some(None) => {
+                unset.insert("scope", "");
+            }
+            None => {}
+        }
+        match key.max_upload_bytes {
+            Some(Some(max_upload_bytes)) => {
+                set.insert("max_upload_bytes", max_upload_bytes.min(i64::MAX as u64) as i64);
+            }
+            Some(None) => {
+                unset.insert("max_upload_bytes", "");
+            }
+            None => {}
+        }
+        let mut doc = doc!{
+            "$set": set,
         };
-        let result = self.collection.update_one(filter, doc, None).await?;
-        Ok(result)
+        if !unset.is_empty() {
+            doc.insert("$unset", unset);
+        }
+        let started_at = Instant::now();
+        let result = self.collection.update_one(filter, doc, None).await;
+        self.metrics.observe_mongodb("update_one", started_at, &result);
+        Ok(result?)
     }
     
     pub async fn get_all(&self) -> Result<Vec<Key>, SimpleApiError> {
-        let mut cursor = self.collection.find(None, None).await?;
+        let started_at = Instant::now();
+        let cursor = self.collection.find(None, None).await;
+        self.metrics.observe_mongodb("find", started_at, &cursor);
+        let mut cursor = cursor?;
         let mut result: Vec<Key> = Vec::new();
         while let Some(doc) = cursor.next().await {
             result.push(Key::convert_bson_to_key(&doc?)?);
@@ -171,7 +247,10 @@ impl ApiKeyProcessor {
         let filter = doc! {
             "key": key.to_string(),
         };
-        let doc = self.collection.find_one(filter, None).await?;
+        let started_at = Instant::now();
+        let doc = self.collection.find_one(filter, None).await;
+        self.metrics.observe_mongodb("find_one", started_at, &doc);
+        let doc = doc?;
         let result = doc.ok_or(SimpleApiError::MongoDBEmptyResult)?;
         let apikey = Key::convert_bson_to_key(&result)?;
 
@@ -182,7 +261,10 @@ impl ApiKeyProcessor {
         let filter = doc! {
             "_id": id,
         };
-        let doc = self.collection.find_one(filter, None).await?;
+        let started_at = Instant::now();
+        let doc = self.collection.find_one(filter, None).await;
+        self.metrics.observe_mongodb("find_one", started_at, &doc);
+        let doc = doc?;
         let result = doc.ok_or(SimpleApiError::MongoDBEmptyResult)?;
         let apikey = Key::convert_bson_to_key(&result)?;
 
@@ -192,7 +274,9 @@ impl ApiKeyProcessor {
         let filter = doc! {
             "key": key.to_string(),
         };
-        let result = self.collection.delete_one(filter, None).await?;
-        Ok(result)
+        let started_at = Instant::now();
+        let result = self.collection.delete_one(filter, None).await;
+        self.metrics.observe_mongodb("delete_one", started_at, &result);
+        Ok(result?)
     }
 }
\ No newline at end of file
@@ -1,17 +1,29 @@
+use std::time::Duration;
 use actix_web::{
     web, 
+    HttpRequest,
     HttpResponse,
     get, 
     post, 
     put, 
     delete, 
 };
+use mongodb::{
+    bson::doc,
+    Database,
+};
 use serde_json::json;
 use super::processor::{
     NewKey,
     UpdateKey,
 };
 use super::processor::JsonError;
+use super::metrics::Metrics;
+use super::trace::RequestContext;
+use super::health::{
+    self,
+    Check,
+};
 
 
 #[get("")]
@@ -29,10 +41,19 @@ async fn get_keys(app_data: web::Data<crate::State>) -> Result<HttpResponse, Jso
 
 #[get("/{key}")]
 async fn get_key(
+    req: HttpRequest,
     key: web::Path<String>,
     app_data: web::Data<crate::State>,
 ) -> Result<HttpResponse, JsonError> {
+    let mut span = req
+        .extensions()
+        .get::<RequestContext>()
+        .map(|context| context.span.client_span("mongodb.find_one"));
     let result = app_data.container.key.get_key(&key).await;
+    if let (Some(span), Err(_)) = (span.as_mut(), &result) {
+        span.set_error();
+    }
+    drop(span);
     match result {
         Ok(apikey) => Ok(HttpResponse::Ok().json(json!({
             "status": 200,
@@ -51,11 +72,11 @@ async fn create(app_data: web::Data<crate::State>) -> Result<HttpResponse, JsonE
     match result {
         Ok(inserted) => {
             let id = inserted.inserted_id.as_object_id().ok_or(JsonError {
-                msg: format!("Insert failed"),
+                msg: "Insert failed".to_string(),
                 status: 500,
                 success: false,
             })?;
-            let apikey = app_data.container.key.get_key_from_id(&id).await;
+            let apikey = app_data.container.key.get_key_from_id(id).await;
             match apikey {
                 Ok(key) => Ok(HttpResponse::Ok().json(json!({
                     "status": 200,
@@ -75,12 +96,13 @@ async fn update(
     app_data: web::Data<crate::State>,
 ) -> Result<HttpResponse, JsonError> {
     let apikey = apikey.into_inner();
-    let result = app_data.container.key.update(apikey).await;
+    let result = app_data.container.key.update(&apikey).await;
     match result {
         Ok(_) => {
             // Result does not return an upserted_id, so we play nice by fetching by key
             // And returning the changed object
             let key = apikey.key.to_hyphenated().to_string();
+            app_data.container.invalidator.invalidate(&key);
             let apikey = app_data.container.key.get_key(&key).await;
             match apikey {
                 Ok(key) => Ok(HttpResponse::Ok().json(json!({
@@ -110,6 +132,7 @@ async fn delete(
                     success: false,
                 })
             } else {
+                app_data.container.invalidator.invalidate(&key);
                 Ok(HttpResponse::Ok().json(json!({
                     "status": 200,
                     "success": true,
@@ -122,3 +145,21 @@ async fn delete(
         Err(e) => Err(e.into()),
     }
 }
+
+#[get("/metrics")]
+async fn get_metrics(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+#[get("/healthz")]
+async fn get_healthz() -> HttpResponse {
+    health::liveness()
+}
+
+#[get("/readyz")]
+async fn get_readyz(database: web::Data<Database>) -> HttpResponse {
+    let mongodb = Check::run(Duration::from_secs(2), database.run_command(doc! { "ping": 1 }, None)).await;
+    health::readiness(vec![("mongodb", mongodb)])
+}